@@ -0,0 +1,275 @@
+/*
+Copyright (C) 2023 Elijah Fry
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see https://www.gnu.org/licenses/gpl-3.0.html.
+*/
+
+
+use arch_crypt::{shamir::MIN_THRESHOLD, MAX_MEMORY_COST, MAX_PARALLELISM, MAX_TIME_COST};
+use clap::{arg, value_parser, Arg, ArgAction, Command};
+
+
+pub fn arguments() -> Command {
+    Command::new(file!())
+    .about("A utility to archive and crypt entire directories")
+    .subcommand_required(true)
+    .arg_required_else_help(true)
+    .subcommand(
+        Command::new("pack") // Pack a directory into a tarball
+            .about("Pack a directory into a tarball")
+            .arg(arg!(<INPUT_DIRECTORY> "The directory to archive"))
+            .arg_required_else_help(true)
+            .arg(arg!(<OUTPUT_FILE> "The name of the output file, or - for stdout"))
+            .arg_required_else_help(true)
+            .arg(arg!(--compress <ALGORITHM> "Compress the tarball (default: picked from the output file's extension, I.E. .tar.gz, .tar.zst or .tar.xz)").value_parser(["none", "gzip", "zstd", "xz"]))
+            .arg(root_argument())
+    )
+    .subcommand(
+        Command::new("unpack") // Unpack a tarball
+            .about("Unpack a tarball (compressed tarballs are detected automatically)")
+            .arg(arg!(<INPUT_FILE> "The tarball to unpack, or - for stdin"))
+            .arg_required_else_help(true)
+            .arg(arg!(<OUTPUT_DIRECTORY> "The directory to unpack the tarball contents in"))
+            .arg_required_else_help(true)
+            .arg(hardened_argument())
+            .arg(strip_components_argument())
+    )
+    .subcommand(
+        Command::new("encrypt") // Encrypt a file
+            .about("Encrypt a file")
+            .arg(arg!(<INPUT_FILE> "The file to encrypt, or - for stdin"))
+            .arg_required_else_help(true)
+            .arg(arg!(<OUTPUT_FILE> "The name of the output file, or - for stdout"))
+            .arg_required_else_help(true)
+            .args(encryption_arguments())
+            .args(kdf_arguments())
+            .args(password_arguments())
+            .args(key_file_arguments())
+            .arg(recipient_argument())
+            .arg(generate_recovery_key_argument())
+            .arg(split_argument())
+    )
+    .subcommand(
+        Command::new("seal") // Pack and encrypt a directory
+            .about("Pack a directory into a tarball and encrypt it, without writing the unencrypted tarball to disk")
+            .arg(arg!(<INPUT_DIRECTORY> "The directory to archive"))
+            .arg_required_else_help(true)
+            .arg(arg!(<OUTPUT_FILE> "The name of the output file"))
+            .arg_required_else_help(true)
+            .arg(root_argument())
+            .args(encryption_arguments())
+            .args(kdf_arguments())
+            .args(password_arguments())
+            .args(key_file_arguments())
+            .arg(recipient_argument())
+            .arg(generate_recovery_key_argument())
+            .arg(split_argument())
+    )
+    .subcommand(
+        Command::new("open") // Decrypt and unpack a sealed directory
+            .about("Decrypt a sealed tarball and unpack it, without writing the decrypted tarball to disk")
+            .arg(arg!(<INPUT_FILE> "The sealed tarball to open"))
+            .arg_required_else_help(true)
+            .arg(arg!(<OUTPUT_DIRECTORY> "The directory to unpack the tarball contents in"))
+            .arg_required_else_help(true)
+            .arg(hardened_argument())
+            .arg(strip_components_argument())
+            .args(password_arguments())
+            .args(key_file_arguments())
+            .arg(private_key_argument())
+            .arg(recovery_key_argument())
+            .arg(share_argument())
+    )
+    .subcommand(
+        Command::new("decrypt") // Decrypt a file
+            .about("Decrypt a file")
+            .arg(arg!(<INPUT_FILE> "The file to decrypt, or - for stdin"))
+            .arg_required_else_help(true)
+            .arg(arg!(<OUTPUT_FILE> "The name of the output file, or - for stdout"))
+            .arg_required_else_help(true)
+            .arg(arg!(--legacy "Decrypt a file that was encrypted by arch-crypt 1.0 (before encrypted files had a header)"))
+            .args(password_arguments())
+            .args(key_file_arguments())
+            .arg(private_key_argument())
+            .arg(recovery_key_argument())
+            .arg(share_argument())
+    )
+    .subcommand(
+        Command::new("list-slots") // List the key slots of an encrypted file
+            .about("List the key slots of an encrypted file, and what's needed to unlock each of them")
+            .arg(arg!(<INPUT_FILE> "The encrypted file"))
+            .arg_required_else_help(true)
+    )
+    .subcommand(
+        Command::new("add-slot") // Add a password, key file or public key to an encrypted file
+            .about("Add a password, key file or public key to an empty key slot of an encrypted file, without re-encrypting it")
+            .arg(arg!(<FILE> "The encrypted file. Only its header is rewritten"))
+            .arg_required_else_help(true)
+            .args(password_arguments())
+            .args(key_file_arguments())
+            .arg(private_key_argument())
+            .arg(recovery_key_argument())
+            .arg(share_argument())
+            .args(new_credential_arguments())
+            .args(kdf_arguments())
+    )
+    .subcommand(
+        Command::new("remove-slot") // Remove a key slot from an encrypted file
+            .about("Empty a key slot of an encrypted file, so its password, key file or private key can't decrypt the file anymore")
+            .arg(arg!(<FILE> "The encrypted file. Only its header is rewritten"))
+            .arg_required_else_help(true)
+            .arg(arg!(<SLOT> "The number of the key slot to empty (see list-slots)").value_parser(value_parser!(usize)))
+            .arg_required_else_help(true)
+            .args(password_arguments())
+            .args(key_file_arguments())
+            .arg(private_key_argument())
+            .arg(recovery_key_argument())
+            .arg(share_argument())
+    )
+    .subcommand(
+        Command::new("rekey") // Change the password of an encrypted file
+            .about("Replace the password, key file or private key that unlocks an encrypted file with a new one, without re-encrypting it")
+            .arg(arg!(<FILE> "The encrypted file. Only its header is rewritten"))
+            .arg_required_else_help(true)
+            .args(password_arguments())
+            .args(key_file_arguments())
+            .arg(private_key_argument())
+            .arg(recovery_key_argument())
+            .arg(share_argument())
+            .args(new_credential_arguments())
+            .args(kdf_arguments())
+    )
+    .subcommand(
+        Command::new("recover") // Finish an interrupted change to the key slots
+            .about("Finish a change to the key slots of an encrypted file that was interrupted (I.E. by a crash), from FILE.header-journal")
+            .arg(arg!(<FILE> "The encrypted file. Only its header is rewritten"))
+            .arg_required_else_help(true)
+    )
+    .subcommand(
+        Command::new("keygen") // Create a key file
+            .about("Create a key file, which can be used instead of or as well as a password")
+            .arg(arg!(<OUTPUT_FILE> "The name of the key file"))
+            .arg_required_else_help(true)
+            .arg(arg!(--x25519 "Create an X25519 key pair instead. The private key is written to OUTPUT_FILE, and the public key is printed and written to OUTPUT_FILE.pub"))
+    )
+}
+
+// Unpacks tarballs that we don't trust safely, shared by every subcommand that unpacks
+fn hardened_argument() -> Arg {
+    arg!(--hardened "Reject (and report) every entry that could end up outside OUTPUT_DIRECTORY: absolute paths, paths containing '..', symlinks and hard links that point outside of it, device nodes and FIFOs. Use this for tarballs you don't trust")
+}
+
+// Removes leading directories from the paths in the tarball, shared by every subcommand that unpacks
+fn strip_components_argument() -> Arg {
+    arg!(--"strip-components" <N> "Remove the first N directories from the path of every entry before unpacking it. Entries that are inside those directories are skipped").value_parser(value_parser!(usize))
+}
+
+// The directory that the packed files are stored under, shared by every subcommand that packs
+fn root_argument() -> Arg {
+    arg!(--root <PATH> "The directory that INPUT_DIRECTORY's contents are stored under in the tarball (default: INPUT_DIRECTORY's name). Use . to store them at the top of the tarball")
+}
+
+// The cipher, compression algorithm and number of key slots, shared by every subcommand that encrypts
+fn encryption_arguments() -> [Arg; 3] {
+    [
+        arg!(--cipher <CIPHER> "The cipher used to encrypt the data (default: chacha20-poly1305)").value_parser(["chacha20-poly1305", "xchacha20-poly1305", "aes-256-gcm-siv"]),
+        arg!(--compress <ALGORITHM> "Compress the data before encrypting it (default: none)").value_parser(["none", "zstd", "snappy"]),
+        arg!(--"key-slots" <SLOTS> "The number of key slots in the header, so more passwords, key files or public keys can be added later with add-slot (default: 8)").value_parser(value_parser!(u8).range(1..))
+    ]
+}
+
+// The Argon2id cost parameters, shared by every subcommand that hashes a new password
+fn kdf_arguments() -> [Arg; 3] {
+    [
+        arg!(--"memory-cost" <KIB> "Argon2id memory cost in KiB (default: 19456, at most 4194304)").value_parser(value_parser!(u32).range(..=MAX_MEMORY_COST as i64)),
+        arg!(--"time-cost" <ITERATIONS> "Argon2id number of iterations (default: 2, at most 64)").value_parser(value_parser!(u32).range(..=MAX_TIME_COST as i64)),
+        arg!(--parallelism <THREADS> "Argon2id degree of parallelism (default: 1, at most 255)").value_parser(value_parser!(u32).range(..=MAX_PARALLELISM as i64))
+    ]
+}
+
+// Where to read the password from instead of prompting for it, shared by every subcommand that needs a password
+fn password_arguments() -> [Arg; 3] {
+    [
+        arg!(--"password-file" <FILE> "Read the password from the first line of a file").conflicts_with_all(["password-fd", "password-env"]),
+        arg!(--"password-fd" <FD> "Read the password from the first line of an open file descriptor (Unix only)").value_parser(value_parser!(u32)).conflicts_with("password-env"),
+        arg!(--"password-env" <VARIABLE> "Read the password from an environment variable, which is then removed from the environment")
+    ]
+}
+
+// The key file, shared by every subcommand that needs a password
+fn key_file_arguments() -> [Arg; 2] {
+    [
+        arg!(--"key-file" <FILE> "Use a key file as well as the password (any file works, see the keygen subcommand)"),
+        arg!(--"no-password" "Only use the key file, without a password").requires("key-file").conflicts_with_all(["password-file", "password-fd", "password-env"])
+    ]
+}
+
+// The public keys to encrypt for, shared by every subcommand that encrypts
+fn recipient_argument() -> Arg {
+    arg!(--recipient <PUBLIC_KEY> "Encrypt for an X25519 public key (x25519:...), see keygen --x25519. Can be given more than once. The password is then only used if it's given with --password-file, --password-fd or --password-env").action(ArgAction::Append)
+}
+
+// The private key to decrypt with, shared by every subcommand that decrypts
+fn private_key_argument() -> Arg {
+    arg!(--"private-key" <FILE> "Decrypt with an X25519 private key file, see keygen --x25519. The password is then only used if it's given with --password-file, --password-fd or --password-env")
+}
+
+// Creates a recovery key, shared by every subcommand that encrypts
+fn generate_recovery_key_argument() -> Arg {
+    arg!(--"generate-recovery-key" "Also create a recovery key, which can decrypt the file without the password (see decrypt --recovery-key). It's printed once, so write it down and keep it somewhere safe")
+}
+
+// The recovery key to decrypt with, shared by every subcommand that decrypts
+// Note: The value must be given with `=`, so `--recovery-key INPUT_FILE` doesn't mistake the input file for the recovery key
+fn recovery_key_argument() -> Arg {
+    arg!(--"recovery-key" [KEY] "Decrypt with the recovery key that was printed when the file was encrypted. It's prompted for, unless it's given with --recovery-key=KEY. The password is then only used if it's given with --password-file, --password-fd or --password-env")
+        .num_args(0..=1)
+        .require_equals(true)
+        .default_missing_value("")
+}
+
+// Splits a new key into Shamir shares, shared by every subcommand that encrypts
+fn split_argument() -> Arg {
+    arg!(--split <THRESHOLD_OF_SHARES> "Split a new key into share files (OUTPUT_FILE.share-1, ...), so that any THRESHOLD of them can decrypt the file, I.E. 3-of-5. The password is then only used if it's given with --password-file, --password-fd or --password-env").value_parser(parse_split)
+}
+
+// The share files to decrypt with, shared by every subcommand that decrypts
+fn share_argument() -> Arg {
+    arg!(--share <FILE> "Decrypt with a share file, see --split. Give it once for every share file, until the threshold is reached. The password is then only used if it's given with --password-file, --password-fd or --password-env").action(ArgAction::Append)
+}
+
+// Parses a Shamir threshold, I.E. `3-of-5`
+fn parse_split(value: &str) -> Result<(u8, u8), String> {
+    let parsed = value.split_once("-of-").and_then(|(threshold, num_shares)| threshold.parse::<u8>().ok().zip(num_shares.parse::<u8>().ok()));
+    match parsed {
+        Some((threshold, num_shares)) if threshold >= MIN_THRESHOLD && threshold <= num_shares => Ok((threshold, num_shares)),
+        Some(_) => Err(format!("the threshold must be between {MIN_THRESHOLD} and the number of shares")),
+        None => Err("expected THRESHOLD-of-SHARES, I.E. 3-of-5 (at most 255 shares)".to_string())
+    }
+}
+
+// The password, key file, public keys, recovery key and shares to add, for the add-slot and rekey subcommands
+// The password is read the same way as the current one, but with --new-password-file, --new-password-fd and --new-password-env
+fn new_credential_arguments() -> [Arg; 8] {
+    [
+        arg!(--"new-password-file" <FILE> "Read the new password from the first line of a file").conflicts_with_all(["new-password-fd", "new-password-env"]),
+        arg!(--"new-password-fd" <FD> "Read the new password from the first line of an open file descriptor (Unix only)").value_parser(value_parser!(u32)).conflicts_with("new-password-env"),
+        arg!(--"new-password-env" <VARIABLE> "Read the new password from an environment variable, which is then removed from the environment"),
+        arg!(--"new-key-file" <FILE> "Add a key file, as well as the new password"),
+        arg!(--"new-no-password" "Only add the new key file, without a new password").requires("new-key-file").conflicts_with_all(["new-password-file", "new-password-fd", "new-password-env"]),
+        arg!(--"new-recipient" <PUBLIC_KEY> "Add an X25519 public key (x25519:...). Can be given more than once. The new password is then only added if it's given with --new-password-file, --new-password-fd or --new-password-env").action(ArgAction::Append),
+        arg!(--"new-generate-recovery-key" "Add a new recovery key. It's printed once, so write it down and keep it somewhere safe. The new password is then only added if it's given with --new-password-file, --new-password-fd or --new-password-env"),
+        arg!(--"new-split" <THRESHOLD_OF_SHARES> "Add a new key split into share files (FILE.share-1, ...), so that any THRESHOLD of them can decrypt the file, I.E. 3-of-5. The new password is then only added if it's given with --new-password-file, --new-password-fd or --new-password-env").value_parser(parse_split)
+    ]
+}
@@ -0,0 +1,74 @@
+/*
+Copyright (C) 2023 Elijah Fry
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see https://www.gnu.org/licenses/gpl-3.0.html.
+*/
+
+
+//! arch-crypt archives entire directories into tarballs, and encrypts files with passwords, key files or X25519 public keys
+//!
+//! The easiest way to use arch-crypt is through the builder-style `Encryptor` and `Decryptor`:
+//!
+//! ```no_run
+//! use arch_crypt::{Cipher, Decryptor, Encryptor};
+//!
+//! let input = std::fs::File::open("notes.txt").unwrap();
+//! let output = std::fs::File::create("notes.txt.enc").unwrap();
+//! Encryptor::new().password("correct horse battery staple").cipher(Cipher::XChaCha20Poly1305).encrypt(input, output).unwrap();
+//!
+//! let input = std::fs::File::open("notes.txt.enc").unwrap();
+//! let mut plaintext = Vec::new();
+//! Decryptor::new().password("correct horse battery staple").decrypt(input, &mut plaintext).unwrap();
+//! ```
+//!
+//! To encrypt or decrypt as you go (I.E. over a socket or a pipe), `Encryptor::writer` and `Decryptor::reader` return an `EncryptingWriter` and a `DecryptingReader`:
+//!
+//! ```
+//! use std::io::{Cursor, Read, Write};
+//! use arch_crypt::{Decryptor, Encryptor};
+//!
+//! let mut writer = Encryptor::new().password("correct horse battery staple").writer(Vec::new()).unwrap();
+//! writer.write_all(b"Hello, world!").unwrap();
+//! let encrypted: Vec<u8> = writer.finish().unwrap();
+//!
+//! let mut reader = Decryptor::new().password("correct horse battery staple").reader(Cursor::new(encrypted)).unwrap();
+//! let mut plaintext = String::new();
+//! reader.read_to_string(&mut plaintext).unwrap();
+//! assert_eq!(plaintext, "Hello, world!");
+//! ```
+//!
+//! To encrypt for someone else without sharing a password, use their X25519 public key (see `x25519::generate_key_pair`).
+//! They decrypt with the matching private key file:
+//!
+//! ```no_run
+//! use arch_crypt::Encryptor;
+//!
+//! let input = std::fs::File::open("notes.txt").unwrap();
+//! let output = std::fs::File::create("notes.txt.enc").unwrap();
+//! Encryptor::new().recipient("x25519:leFl/wuwh3m7KgM/+tPGih+dSBfe8nymCOHR11vLbTg=").encrypt(input, output).unwrap();
+//! ```
+//!
+//! The lower-level functions for tarballs live in the `archiver` module, and the key slots of an encrypted file can be changed with the `key_slots` module.
+//! Every function returns an `arch_crypt::Result`, and the `Error` tells you what went wrong (I.E. an incorrect password or a corrupted file)
+
+
+mod modules;
+
+pub use modules::{archiver, key_file, key_slots, recovery_key, shamir, x25519};
+pub use modules::archiver::{PackOptions, UnpackOptions};
+pub use modules::builder::{Decryptor, Encryptor};
+pub use modules::compression::compression_from_file_name;
+pub use modules::encryption::{decrypt_legacy_file, Credentials, DecryptingReader, EncryptingWriter, EncryptionOptions};
+pub use modules::error::{Error, Result};
+pub use modules::header::{Cipher, Compression, KdfParams, Recipient, MAX_MEMORY_COST, MAX_PARALLELISM, MAX_TIME_COST};
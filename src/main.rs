@@ -18,13 +18,18 @@ along with this program.  If not, see https://www.gnu.org/licenses/gpl-3.0.html.
 
 // #![allow(unused)]
 // #![allow(deprecated)]
-use std::io::{BufRead, Write};
-use log::{info, error};
-mod modules;
-use modules::{encryption::{decrypt_file, encrypt_file}, archiver::{unpack, pack}, cli_args};
+use std::{fs::{File, OpenOptions}, io::{BufRead, BufReader, BufWriter, Read, Write}, path::PathBuf};
+use clap::ArgMatches;
+use log::{info, error, warn};
+mod cli_args;
+use arch_crypt::{archiver::{pack, pack_to, unpack, unpack_from, PackOptions, UnpackOptions}, compression_from_file_name, decrypt_legacy_file, Credentials, EncryptionOptions, Recipient, key_slots::{add_key_slots, explain_modified_header, finish_interrupted_rewrite, has_interrupted_rewrite, list_key_slots, rekey, remove_key_slot}, key_file::{generate_key_file, read_key_file}, recovery_key::{format_recovery_key, generate_recovery_key, parse_recovery_key}, shamir::{generate_shares, read_share_file, write_share_files}, x25519::{generate_key_pair, parse_public_key, read_private_key}, Cipher, Compression, Decryptor, Encryptor, Error, KdfParams};
 use zeroize::Zeroize;
 
 
+// An input or output file named `-` means stdin or stdout
+const STANDARD_STREAM: &str = "-";
+
+
 fn main() {
     // Initialize logger
     env_logger::builder().format_timestamp(None).filter_level(log::LevelFilter::Trace).init();
@@ -41,12 +46,23 @@ fn main() {
         let input_directory = sub_matches.get_one::<String>("INPUT_DIRECTORY").unwrap().to_owned();
         let output_file = sub_matches.get_one::<String>("OUTPUT_FILE").unwrap().to_owned();
 
+        // Use the requested compression algorithm, or pick one based on the output file's extension
+        // Note: We can call .unwrap() on Compression::from_name because Clap only accepts the names it knows about
+        let compression = match sub_matches.get_one::<String>("compress") {
+            Some(name) => Compression::from_name(name).unwrap(),
+            None => compression_from_file_name(&output_file)
+        };
+
         // Pack the directory (and its contents) into a tarball
-        match pack(input_directory, output_file) {
+        let result = if output_file == STANDARD_STREAM {
+            pack_to(input_directory, BufWriter::new(std::io::stdout().lock()), compression, pack_options_from_arguments(sub_matches)).map(|_resp| ())
+        } else {
+            pack(input_directory, output_file, compression, pack_options_from_arguments(sub_matches))
+        };
+        match result {
             Ok(_resp) => {},
-            Err(_error) => {
-                error!("Failed to pack tarball");
-                return;
+            Err(error) => {
+                exit_with_error("Failed to pack tarball", error);
             },
         };
     }
@@ -58,13 +74,78 @@ fn main() {
         // Note: We can call .unwrap() on these arguments because they are required, and Clap ensures that they were provided
         let input_file = sub_matches.get_one::<String>("INPUT_FILE").unwrap().to_owned();
         let output_directory = sub_matches.get_one::<String>("OUTPUT_DIRECTORY").unwrap().to_owned();
+        let unpack_options = unpack_options_from_arguments(sub_matches);
 
         // Unpack the contents of a tarball into a directory
-        match unpack(input_file, output_directory) {
+        let result = if input_file == STANDARD_STREAM {
+            unpack_from(std::io::stdin().lock(), output_directory, unpack_options)
+        } else {
+            unpack(input_file, output_directory, unpack_options)
+        };
+        match result {
             Ok(_resp) => {},
+            Err(error) => {
+                exit_with_error("Failed to unpack tarball", error);
+            },
+        };
+    }
+
+    if let Some(("seal", sub_matches)) = cli_arguments.subcommand() {
+        info!("User requested 'seal'");
+
+        // Parse arguments
+        // Note: We can call .unwrap() on these arguments because they are required, and Clap ensures that they were provided
+        let input_directory = sub_matches.get_one::<String>("INPUT_DIRECTORY").unwrap().to_owned();
+        let output_file = sub_matches.get_one::<String>("OUTPUT_FILE").unwrap().to_owned();
+        let encryption_options = encryption_options_from_arguments(sub_matches);
+
+        // Get the password from the user (or from where they told us to read it from), and read the key file if there is one
+        let credentials = match credentials_from_arguments(sub_matches, "", true, false) {
+            Ok(resp) => {
+                resp
+            },
             Err(_error) => {
-                error!("Failed to unpack tarball");
-                return;
+                std::process::exit(1);
+            }
+        };
+
+        // Pack and encrypt the directory (and its contents) in one pass
+        let recovery_key = credentials.recovery_key.as_ref().map(format_recovery_key);
+        let share_files = write_shares(&output_file, &credentials);
+        match Encryptor::new().credentials(credentials).options(encryption_options).pack_options(pack_options_from_arguments(sub_matches)).seal(input_directory, output_file) {
+            Ok(_resp) => {
+                show_recovery_key(recovery_key);
+            },
+            Err(error) => {
+                remove_share_files(&share_files);
+                exit_with_error("Failed to seal tarball", error);
+            },
+        };
+    }
+
+    if let Some(("open", sub_matches)) = cli_arguments.subcommand() {
+        info!("User requested 'open'");
+
+        // Parse arguments
+        // Note: We can call .unwrap() on these arguments because they are required, and Clap ensures that they were provided
+        let input_file = sub_matches.get_one::<String>("INPUT_FILE").unwrap().to_owned();
+        let output_directory = sub_matches.get_one::<String>("OUTPUT_DIRECTORY").unwrap().to_owned();
+
+        // Get the password from the user (or from where they told us to read it from), and read the key file if there is one
+        let credentials = match credentials_from_arguments(sub_matches, "", false, false) {
+            Ok(resp) => {
+                resp
+            },
+            Err(_error) => {
+                std::process::exit(1);
+            }
+        };
+
+        // Decrypt and unpack the sealed tarball in one pass
+        match Decryptor::new().credentials(credentials).unpack_options(unpack_options_from_arguments(sub_matches)).open(input_file, output_directory) {
+            Ok(_resp) => {},
+            Err(error) => {
+                exit_with_error("Failed to open sealed tarball", error);
             },
         };
     }
@@ -77,18 +158,225 @@ fn main() {
         let input_file = sub_matches.get_one::<String>("INPUT_FILE").unwrap().to_owned();
         let output_file = sub_matches.get_one::<String>("OUTPUT_FILE").unwrap().to_owned();
 
-        // Prompt user for a password and hash it into encryption key
-        let plaintext_password = match prompt_user_for_password(true) {
+        let encryption_options = encryption_options_from_arguments(sub_matches);
+
+        // Get the password from the user (or from where they told us to read it from), and read the key file if there is one
+        // Note: If the input file is stdin, the password is read from the terminal instead
+        let credentials = match credentials_from_arguments(sub_matches, "", true, input_file == STANDARD_STREAM) {
             Ok(resp) => {
                 resp
             },
             Err(_error) => {
-                return;
+                std::process::exit(1);
             }
         };
 
         // Encrypt the file
-        let _ =  encrypt_file(input_file, output_file, plaintext_password);
+        let recovery_key = credentials.recovery_key.as_ref().map(format_recovery_key);
+        let share_files = write_shares(&output_file, &credentials);
+        let encryptor = Encryptor::new().credentials(credentials).options(encryption_options);
+        let result = if input_file == STANDARD_STREAM || output_file == STANDARD_STREAM {
+            encrypt_stream(&input_file, &output_file, encryptor)
+        } else {
+            encryptor.encrypt_file(input_file, output_file)
+        };
+        match result {
+            Ok(_resp) => {
+                show_recovery_key(recovery_key);
+            },
+            Err(error) => {
+                remove_share_files(&share_files);
+                exit_with_error("Failed to encrypt file", error);
+            },
+        };
+    }
+
+    if let Some(("list-slots", sub_matches)) = cli_arguments.subcommand() {
+        info!("User requested 'list-slots'");
+
+        // Parse arguments
+        // Note: We can call .unwrap() on this argument because it's required, and Clap ensures that it was provided
+        let input_file = sub_matches.get_one::<String>("INPUT_FILE").unwrap().to_owned();
+
+        // Print every key slot, and what's needed to unlock it
+        // Note: Listing never writes to the file, so an interrupted change to the key slots is only pointed out here
+        match list_key_slots(&input_file) {
+            Ok(resp) => {
+                for (index, slot) in resp.iter().enumerate() {
+                    println!("Key slot {index}: {}", describe_key_slot(slot));
+                }
+                if has_interrupted_rewrite(&input_file) {
+                    warn!("A change to the key slots of '{input_file}' was interrupted, so these may be the key slots from before it. Run 'arch-crypt recover {input_file}' to finish the change");
+                }
+            },
+            Err(error) => {
+                exit_with_error("Failed to list key slots", error);
+            },
+        };
+    }
+
+    if let Some(("add-slot", sub_matches)) = cli_arguments.subcommand() {
+        info!("User requested 'add-slot'");
+
+        // Parse arguments
+        // Note: We can call .unwrap() on this argument because it's required, and Clap ensures that it was provided
+        let file = sub_matches.get_one::<String>("FILE").unwrap().to_owned();
+
+        // Get the credentials that can already decrypt the file, then the ones to add
+        let credentials = match credentials_from_arguments(sub_matches, "", false, false) {
+            Ok(resp) => {
+                resp
+            },
+            Err(_error) => {
+                std::process::exit(1);
+            }
+        };
+        let new_credentials = match credentials_from_arguments(sub_matches, "new-", true, false) {
+            Ok(resp) => {
+                resp
+            },
+            Err(_error) => {
+                std::process::exit(1);
+            }
+        };
+
+        // Add the new credentials to the empty key slots
+        let recovery_key = new_credentials.recovery_key.as_ref().map(format_recovery_key);
+        let share_files = write_shares(&file, &new_credentials);
+        match add_key_slots(&file, credentials, new_credentials, kdf_params_from_arguments(sub_matches)) {
+            Ok(resp) => {
+                for index in resp {
+                    info!("Added key slot {index}");
+                }
+                show_recovery_key(recovery_key);
+            },
+            Err(error) => {
+                remove_share_files(&share_files);
+                exit_with_error("Failed to add key slot", error);
+            },
+        };
+    }
+
+    if let Some(("remove-slot", sub_matches)) = cli_arguments.subcommand() {
+        info!("User requested 'remove-slot'");
+
+        // Parse arguments
+        // Note: We can call .unwrap() on these arguments because they are required, and Clap ensures that they were provided
+        let file = sub_matches.get_one::<String>("FILE").unwrap().to_owned();
+        let slot = *sub_matches.get_one::<usize>("SLOT").unwrap();
+
+        // Get the credentials that can decrypt the file
+        let credentials = match credentials_from_arguments(sub_matches, "", false, false) {
+            Ok(resp) => {
+                resp
+            },
+            Err(_error) => {
+                std::process::exit(1);
+            }
+        };
+
+        // Empty the key slot
+        match remove_key_slot(&file, credentials, slot) {
+            Ok(_resp) => {
+                info!("Removed key slot {slot}");
+            },
+            Err(error) => {
+                exit_with_error("Failed to remove key slot", error);
+            },
+        };
+    }
+
+    if let Some(("rekey", sub_matches)) = cli_arguments.subcommand() {
+        info!("User requested 'rekey'");
+
+        // Parse arguments
+        // Note: We can call .unwrap() on this argument because it's required, and Clap ensures that it was provided
+        let file = sub_matches.get_one::<String>("FILE").unwrap().to_owned();
+
+        // Get the credentials to replace, then the new ones
+        let credentials = match credentials_from_arguments(sub_matches, "", false, false) {
+            Ok(resp) => {
+                resp
+            },
+            Err(_error) => {
+                std::process::exit(1);
+            }
+        };
+        let new_credentials = match credentials_from_arguments(sub_matches, "new-", true, false) {
+            Ok(resp) => {
+                resp
+            },
+            Err(_error) => {
+                std::process::exit(1);
+            }
+        };
+
+        // Replace the key slot of the old credentials
+        let recovery_key = new_credentials.recovery_key.as_ref().map(format_recovery_key);
+        let share_files = write_shares(&file, &new_credentials);
+        match rekey(&file, credentials, new_credentials, kdf_params_from_arguments(sub_matches)) {
+            Ok(resp) => {
+                for index in resp {
+                    info!("Key slot {index} now holds the new credentials");
+                }
+                show_recovery_key(recovery_key);
+            },
+            Err(error) => {
+                remove_share_files(&share_files);
+                exit_with_error("Failed to rekey file", error);
+            },
+        };
+    }
+
+    if let Some(("recover", sub_matches)) = cli_arguments.subcommand() {
+        info!("User requested 'recover'");
+
+        // Parse arguments
+        // Note: We can call .unwrap() on this argument because it's required, and Clap ensures that it was provided
+        let file = sub_matches.get_one::<String>("FILE").unwrap().to_owned();
+
+        // Write the new header from the journal, if there is one
+        match finish_interrupted_rewrite(&file) {
+            Ok(true) => {
+                info!("Finished the interrupted change to the key slots of '{file}'");
+            },
+            Ok(false) => {
+                info!("There was no interrupted change to the key slots of '{file}' to finish");
+            },
+            Err(error) => {
+                exit_with_error("Failed to recover the header", error);
+            },
+        };
+    }
+
+    if let Some(("keygen", sub_matches)) = cli_arguments.subcommand() {
+        info!("User requested 'keygen'");
+
+        // Parse arguments
+        // Note: We can call .unwrap() on this argument because it's required, and Clap ensures that it was provided
+        let output_file = sub_matches.get_one::<String>("OUTPUT_FILE").unwrap().to_owned();
+
+        // Create the key file, or the X25519 key pair
+        if sub_matches.get_flag("x25519") {
+            match generate_key_pair(&output_file) {
+                Ok(resp) => {
+                    info!("Created the private key file '{output_file}' and the public key file '{output_file}.pub'. Keep the private key safe, because files encrypted for the public key can't be decrypted without it");
+                    println!("{resp}");
+                },
+                Err(error) => {
+                    exit_with_error("Failed to create key pair", error);
+                },
+            };
+        } else {
+            match generate_key_file(&output_file) {
+                Ok(_resp) => {
+                    info!("Created the key file '{output_file}'. Keep it safe, because files encrypted with it can't be decrypted without it");
+                },
+                Err(error) => {
+                    exit_with_error("Failed to create key file", error);
+                },
+            };
+        }
     }
 
     if let Some(("decrypt", sub_matches)) = cli_arguments.subcommand() {
@@ -98,84 +386,553 @@ fn main() {
         // Note: We can call .unwrap() on these arguments because they are required, and Clap ensures that they were provided
         let input_file = sub_matches.get_one::<String>("INPUT_FILE").unwrap().to_owned();
         let output_file = sub_matches.get_one::<String>("OUTPUT_FILE").unwrap().to_owned();
+        let legacy = sub_matches.get_flag("legacy");
+
+        // Files in the legacy format are decrypted in place, so they must be real files
+        if legacy && (input_file == STANDARD_STREAM || output_file == STANDARD_STREAM) {
+            exit_with_error("Failed to decrypt file", Error::UnsupportedFormat("Files in the legacy format can't be decrypted from stdin or to stdout".to_string()));
+        }
+        if legacy && sub_matches.contains_id("key-file") {
+            exit_with_error("Failed to decrypt file", Error::UnsupportedFormat("Files in the legacy format can't be encrypted with a key file".to_string()));
+        }
+        if legacy && sub_matches.contains_id("private-key") {
+            exit_with_error("Failed to decrypt file", Error::UnsupportedFormat("Files in the legacy format can't be encrypted for a public key".to_string()));
+        }
+        if legacy && sub_matches.contains_id("recovery-key") {
+            exit_with_error("Failed to decrypt file", Error::UnsupportedFormat("Files in the legacy format don't have a recovery key".to_string()));
+        }
+        if legacy && sub_matches.contains_id("share") {
+            exit_with_error("Failed to decrypt file", Error::UnsupportedFormat("Files in the legacy format can't be split into shares".to_string()));
+        }
 
-        // Prompt user for a password and hash it into encryption key
-        let plaintext_password = match prompt_user_for_password(false) {
+        // Get the password from the user (or from where they told us to read it from), and read the key file if there is one
+        // Note: If the input file is stdin, the password is read from the terminal instead
+        let mut credentials = match credentials_from_arguments(sub_matches, "", false, input_file == STANDARD_STREAM) {
             Ok(resp) => {
                 resp
             },
             Err(_error) => {
-                return;
+                std::process::exit(1);
             }
         };
 
         // Decrypt file
-        let _ = decrypt_file(input_file.clone(), output_file, plaintext_password);
+        let result = if legacy {
+            decrypt_legacy_file(input_file, output_file, credentials.password.take().unwrap_or_default())
+        } else if input_file == STANDARD_STREAM || output_file == STANDARD_STREAM {
+            decrypt_stream(&input_file, &output_file, Decryptor::new().credentials(credentials))
+        } else {
+            Decryptor::new().credentials(credentials).decrypt_file(input_file, output_file)
+        };
+        match result {
+            Ok(_resp) => {},
+            Err(error) => {
+                exit_with_error("Failed to decrypt file", error);
+            },
+        };
     }
 
 }
 
-fn prompt_user_for_password(should_confirm_password: bool) -> Result<String, ()> {
+// Logs an error along with everything that caused it, then exits with a status code that tells scripts what went wrong
+fn exit_with_error(message: &str, error: Error) -> ! {
+    error!("{message}: {error}");
+    let mut source = std::error::Error::source(&error);
+    while let Some(cause) = source {
+        error!("  Caused by: {cause}");
+        source = cause.source();
+    }
+
+    let exit_code = match error {
+        Error::Io { .. } => 1,
+        Error::WrongPassword => 2,
+        Error::Corrupted(_) => 3,
+        Error::UnsupportedFormat(_) => 4,
+        Error::OutputExists(_) => 5,
+        Error::Crypto(_) => 6,
+        // Errors added to the library later are treated as I/O errors until they get their own exit code
+        _ => 1
+    };
+    std::process::exit(exit_code);
+}
 
-    // Initialize variables
-    let mut password = String::new();
+// Opens the input file, or stdin if the input file is `-`
+fn open_input(input_file: &str) -> Result<Box<dyn Read>, Error> {
+    if input_file == STANDARD_STREAM {
+        return Ok(Box::new(std::io::stdin().lock()));
+    }
 
-    // Prompt the user for a passsword
-    print!("Input a password\n> ");
-    match std::io::stdout().flush() {
-        Ok(_resp) => {},
+    match File::open(input_file) {
+        Ok(resp) => Ok(Box::new(BufReader::new(resp))),
+        Err(error) => Err(Error::io(format!("Couldn't open the input file '{input_file}'"), error))
+    }
+}
+
+// Creates the output file (unless it already exists), or uses stdout if the output file is `-`
+fn create_output(output_file: &str) -> Result<Box<dyn Write>, Error> {
+    if output_file == STANDARD_STREAM {
+        return Ok(Box::new(BufWriter::new(std::io::stdout().lock())));
+    }
+
+    match OpenOptions::new().write(true).create_new(true).open(output_file) {
+        Ok(resp) => Ok(Box::new(BufWriter::new(resp))),
+        Err(error) => Err(Error::create(output_file, error))
+    }
+}
+
+// Encrypts from stdin and/or to stdout. If the output is a file, it's deleted if encryption fails
+fn encrypt_stream(input_file: &str, output_file: &str, encryptor: Encryptor) -> Result<(), Error> {
+    let input = match open_input(input_file) {
+        Ok(resp) => resp,
         Err(error) => {
-            error!("Failed to flush the output (stdout) stream:\n {error}");
-            return Err(());
+            return Err(error);
+        }
+    };
+    let output = match create_output(output_file) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(error);
         }
     };
 
-    let mut stdin = std::io::stdin().lock();
-    match stdin.read_line(&mut password) {
-        Ok(_resp) => {},
+    match encryptor.encrypt(input, output) {
+        Ok(_resp) => Ok(()),
         Err(error) => {
-            error!("Failed to read input password:\n {error}");
-            return Err(());
+            if output_file != STANDARD_STREAM {
+                let _ = std::fs::remove_file(output_file);
+            }
+            Err(error)
+        }
+    }
+}
+
+// Decrypts from stdin and/or to stdout. The password is checked before the output is created, and if the output is a file, it's deleted if decryption fails
+fn decrypt_stream(input_file: &str, output_file: &str, decryptor: Decryptor) -> Result<(), Error> {
+    let input = match open_input(input_file) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(error);
+        }
+    };
+    let mut decrypting_reader = match decryptor.reader(input) {
+        Ok(resp) => resp,
+        Err(error) if input_file != STANDARD_STREAM => {
+            return Err(explain_modified_header(input_file, error));
+        },
+        Err(error) => {
+            return Err(error);
+        }
+    };
+    let mut output = match create_output(output_file) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(error);
         }
     };
 
-    // Remove special characters from the password
-    let password = password.trim().to_string();
+    let result = match std::io::copy(&mut decrypting_reader, &mut output) {
+        Ok(_resp) => output.flush(),
+        Err(error) => Err(error)
+    };
+    match result {
+        Ok(_resp) => Ok(()),
+        Err(error) => {
+            if output_file != STANDARD_STREAM {
+                let _ = std::fs::remove_file(output_file);
+            }
+            Err(Error::io("Failed to decrypt the input file", error))
+        }
+    }
+}
 
-    if should_confirm_password {
-        // Initialize annother variable for the confirmation password
-        let mut password_again = String::new();
+// Uses the default cipher, the default Argon2id parameters, no compression and 8 key slots, unless the user asked for something else
+fn encryption_options_from_arguments(sub_matches: &ArgMatches) -> EncryptionOptions {
+    // Note: We can call .unwrap() on Cipher::from_name and Compression::from_name because Clap only accepts the names they know about
+    let cipher = match sub_matches.get_one::<String>("cipher") {
+        Some(name) => Cipher::from_name(name).unwrap(),
+        None => Cipher::default()
+    };
 
-        // Prompt the user to confirm their password
-        print!("Confirm your password\n> ");
-        match std::io::stdout().flush() {
-            Ok(_resp) => {},
+    let compression = match sub_matches.get_one::<String>("compress") {
+        Some(name) => Compression::from_name(name).unwrap(),
+        None => Compression::None
+    };
+
+    EncryptionOptions {
+        cipher,
+        kdf_params: kdf_params_from_arguments(sub_matches),
+        compression,
+        key_slots: sub_matches.get_one::<u8>("key-slots").copied().unwrap_or(EncryptionOptions::default().key_slots)
+    }
+}
+
+// Packs the directory under its own name, unless the user asked for another root
+fn pack_options_from_arguments(sub_matches: &ArgMatches) -> PackOptions {
+    PackOptions {
+        root: sub_matches.get_one::<String>("root").map(PathBuf::from)
+    }
+}
+
+// Unpacks with the default options, unless the user asked for a hardened unpack or to strip leading directories
+fn unpack_options_from_arguments(sub_matches: &ArgMatches) -> UnpackOptions {
+    UnpackOptions {
+        hardened: sub_matches.get_flag("hardened"),
+        strip_components: sub_matches.get_one::<usize>("strip-components").copied().unwrap_or(0)
+    }
+}
+
+// Describes what's needed to unlock a key slot, for list-slots
+fn describe_key_slot(slot: &Option<Recipient>) -> String {
+    match slot {
+        Some(Recipient::Password { kdf_params, .. }) => format!("password (Argon2id memory cost: {} KiB, time cost: {}, parallelism: {})", kdf_params.memory_cost, kdf_params.time_cost, kdf_params.parallelism),
+        Some(Recipient::KeyFile { .. }) => "key file".to_string(),
+        Some(Recipient::PasswordAndKeyFile { kdf_params, .. }) => format!("password and key file (Argon2id memory cost: {} KiB, time cost: {}, parallelism: {})", kdf_params.memory_cost, kdf_params.time_cost, kdf_params.parallelism),
+        Some(Recipient::X25519 { .. }) => "X25519 private key".to_string(),
+        Some(Recipient::RecoveryKey { .. }) => "recovery key".to_string(),
+        Some(Recipient::Shares { threshold, num_shares, .. }) => format!("any {threshold} of {num_shares} shares"),
+        Some(recipient) => recipient.description().to_string(),
+        None => "empty".to_string()
+    }
+}
+
+// Reads the Argon2id cost parameters from the arguments, using the defaults for the ones that weren't given
+fn kdf_params_from_arguments(sub_matches: &ArgMatches) -> KdfParams {
+    let default_kdf_params = KdfParams::default();
+    KdfParams {
+        memory_cost: sub_matches.get_one::<u32>("memory-cost").copied().unwrap_or(default_kdf_params.memory_cost),
+        time_cost: sub_matches.get_one::<u32>("time-cost").copied().unwrap_or(default_kdf_params.time_cost),
+        parallelism: sub_matches.get_one::<u32>("parallelism").copied().unwrap_or(default_kdf_params.parallelism)
+    }
+}
+
+// Reads the key file (if --key-file was given), the public keys (--recipient) or the private key (--private-key), the recovery key, the shares, and the password (unless --no-password was given)
+// Note: If there are public keys, a private key, shares or a recovery key to decrypt with, the password is only used if it was given with --password-file, --password-fd or --password-env
+// The new credentials of add-slot are read with the same arguments, prefixed with `new-` (I.E. --new-key-file)
+fn credentials_from_arguments(sub_matches: &ArgMatches, prefix: &str, should_confirm_password: bool, stdin_is_input: bool) -> Result<Credentials, ()> {
+    let mut credentials = Credentials::default();
+
+    if let Some(key_file) = sub_matches.get_one::<String>(&format!("{prefix}key-file")) {
+        credentials.key_file = match read_key_file(key_file) {
+            Ok(resp) => Some(resp),
+            Err(error) => {
+                exit_with_error("Failed to read the key file", error);
+            }
+        };
+    }
+
+    // Only the subcommands that encrypt (or add key slots) have --recipient, and only the ones that decrypt have --private-key
+    if let Ok(Some(public_keys)) = sub_matches.try_get_many::<String>(&format!("{prefix}recipient")) {
+        for public_key in public_keys {
+            match parse_public_key(public_key) {
+                Ok(resp) => credentials.recipients.push(resp),
+                Err(error) => {
+                    exit_with_error("Failed to read the recipients", error);
+                }
+            };
+        }
+    }
+    if let Ok(Some(private_key_file)) = sub_matches.try_get_one::<String>(&format!("{prefix}private-key")) {
+        credentials.private_key = match read_private_key(private_key_file) {
+            Ok(resp) => Some(resp),
+            Err(error) => {
+                exit_with_error("Failed to read the private key", error);
+            }
+        };
+    }
+
+    // Only the subcommands that encrypt (or add key slots) can create a recovery key, and only the ones that decrypt have --recovery-key
+    // Note: A recovery key created by encrypt is kept as well as the password, but add-slot can add one on its own
+    let mut has_recovery_key = false;
+    if let Ok(Some(&true)) = sub_matches.try_get_one::<bool>(&format!("{prefix}generate-recovery-key")) {
+        credentials.recovery_key = Some(generate_recovery_key());
+        has_recovery_key = !prefix.is_empty();
+    }
+    if let Ok(Some(recovery_key)) = sub_matches.try_get_one::<String>(&format!("{prefix}recovery-key")) {
+        let mut recovery_key = if recovery_key.is_empty() {
+            match prompt_user_for_recovery_key(stdin_is_input) {
+                Ok(resp) => resp,
+                Err(_error) => {
+                    return Err(());
+                }
+            }
+        } else {
+            recovery_key.to_owned()
+        };
+        let parsed_recovery_key = parse_recovery_key(&recovery_key);
+        recovery_key.zeroize();
+        credentials.recovery_key = match parsed_recovery_key {
+            Ok(resp) => Some(resp),
+            Err(error) => {
+                exit_with_error("Failed to read the recovery key", error);
+            }
+        };
+        has_recovery_key = true;
+    }
+
+    // Only the subcommands that encrypt (or add key slots) have --split, and only the ones that decrypt have --share
+    if let Ok(Some((threshold, num_shares))) = sub_matches.try_get_one::<(u8, u8)>(&format!("{prefix}split")) {
+        credentials.shares = match generate_shares(*threshold, *num_shares) {
+            Ok(resp) => resp,
             Err(error) => {
-                error!("Failed to flush the output (stdout) stream:\n {error}");
+                exit_with_error("Failed to split the key", error);
+            }
+        };
+    }
+    if let Ok(Some(share_files)) = sub_matches.try_get_many::<String>(&format!("{prefix}share")) {
+        for share_file in share_files {
+            match read_share_file(share_file) {
+                Ok(resp) => credentials.shares.push(resp),
+                Err(error) => {
+                    exit_with_error("Failed to read the shares", error);
+                }
+            };
+        }
+    }
+
+    // Don't prompt for a password when a public key, private key, recovery key or shares can be used instead
+    let has_password_source = ["password-file", "password-fd", "password-env"].iter().any(|id| sub_matches.contains_id(&format!("{prefix}{id}")));
+    let has_public_key_credentials = !credentials.recipients.is_empty() || credentials.private_key.is_some();
+    if !sub_matches.get_flag(&format!("{prefix}no-password")) && (has_password_source || !(has_public_key_credentials || has_recovery_key || !credentials.shares.is_empty())) {
+        credentials.password = match password_from_arguments(sub_matches, prefix, should_confirm_password, stdin_is_input) {
+            Ok(resp) => Some(resp),
+            Err(_error) => {
                 return Err(());
             }
         };
-        match stdin.read_line(&mut password_again) {
-            Ok(_resp) => {},
+    }
+
+    Ok(credentials)
+}
+
+// Reads the password from --password-file, --password-fd or --password-env, or prompts the user for it if none of them were given
+// Note: Passwords from these sources are never confirmed, and they can't be empty
+fn password_from_arguments(sub_matches: &ArgMatches, prefix: &str, should_confirm_password: bool, stdin_is_input: bool) -> Result<String, ()> {
+    if let Some(password_file) = sub_matches.get_one::<String>(&format!("{prefix}password-file")) {
+        return match File::open(password_file) {
+            Ok(resp) => read_password_from(resp, &format!("the password file '{password_file}'")),
+            Err(error) => {
+                error!("Failed to open the password file '{password_file}':\n {error}");
+                Err(())
+            }
+        };
+    }
+
+    if let Some(password_fd) = sub_matches.get_one::<u32>(&format!("{prefix}password-fd")) {
+        return read_password_from_fd(*password_fd, stdin_is_input);
+    }
+
+    if let Some(variable) = sub_matches.get_one::<String>(&format!("{prefix}password-env")) {
+        // Remove the variable as soon as it's read, so the password isn't passed on to anything we run later
+        // Note: This happens before any other threads are started, so nothing else can be reading the environment at the same time
+        let password_variable = std::env::var(variable);
+        std::env::remove_var(variable);
+        return match password_variable {
+            Ok(mut resp) => {
+                let password = first_line_as_password(&resp, &format!("the environment variable '{variable}'"));
+                resp.zeroize();
+                password
+            },
             Err(error) => {
-                error!("Failed to read input password:\n {error}");
+                error!("Failed to read the password from the environment variable '{variable}':\n {error}");
+                Err(())
+            }
+        };
+    }
+
+    prompt_user_for_password(prefix, should_confirm_password, stdin_is_input)
+}
+
+// Reads the password from an open file descriptor, through /dev/fd
+#[cfg(unix)]
+fn read_password_from_fd(password_fd: u32, stdin_is_input: bool) -> Result<String, ()> {
+    if password_fd == 0 && stdin_is_input {
+        error!("Can't read the password from stdin, because it's being used for the input file");
+        return Err(());
+    }
+    match File::open(format!("/dev/fd/{password_fd}")) {
+        Ok(resp) => read_password_from(resp, &format!("file descriptor {password_fd}")),
+        Err(error) => {
+            error!("Failed to open file descriptor {password_fd}:\n {error}");
+            Err(())
+        }
+    }
+}
+
+// File descriptors can only be opened by their number on Unix
+#[cfg(not(unix))]
+fn read_password_from_fd(_password_fd: u32, _stdin_is_input: bool) -> Result<String, ()> {
+    error!("--password-fd is only supported on Unix, use --password-file or --password-env instead");
+    Err(())
+}
+
+// Reads everything from a file (or file descriptor) and uses its first line as the password
+fn read_password_from<R: Read>(mut reader: R, source: &str) -> Result<String, ()> {
+    let mut contents = String::new();
+    if let Err(error) = reader.read_to_string(&mut contents) {
+        contents.zeroize();
+        error!("Failed to read the password from {source}:\n {error}");
+        return Err(());
+    }
+
+    // Flush the memory that holds the contents with 0s, now that we've copied the password out of it
+    let password = first_line_as_password(&contents, source);
+    contents.zeroize();
+    password
+}
+
+// Uses the first line of the contents as the password. Leading and trailing whitespace is removed, just like the prompt does
+fn first_line_as_password(contents: &str, source: &str) -> Result<String, ()> {
+    let password = contents.lines().next().unwrap_or_default().trim().to_string();
+    if password.is_empty() {
+        error!("The password from {source} is empty");
+        return Err(());
+    }
+    Ok(password)
+}
+
+// Prompts the user for a password on the terminal, without echoing it
+// Note: If there's no terminal (I.E. in a script), the password is read from stdin instead, unless stdin is being used for the input file
+fn prompt_user_for_password(prefix: &str, should_confirm_password: bool, stdin_is_input: bool) -> Result<String, ()> {
+    let (prompt, confirmation_prompt) = if prefix.is_empty() {
+        ("Input a password\n> ", "Confirm your password\n> ")
+    } else {
+        ("Input the new password\n> ", "Confirm the new password\n> ")
+    };
+
+    // Prompt the user for a passsword
+    let (password, from_terminal) = match rpassword::prompt_password(prompt) {
+        Ok(resp) => (resp, true),
+        Err(error) => {
+            if stdin_is_input {
+                error!("Failed to read the password from the terminal (stdin is being used for the input file):\n {error}");
                 return Err(());
             }
+            warn!("There's no terminal to read the password from, so it will be read from stdin");
+            match read_password_from_stdin(prompt) {
+                Ok(resp) => (resp, false),
+                Err(_error) => {
+                    return Err(());
+                }
+            }
+        }
+    };
+
+    // Remove special characters from the password
+    let mut password = trim_password(password);
+
+    if should_confirm_password {
+        // Prompt the user to confirm their password, in the same place that we read the password from
+        let password_again = if from_terminal {
+            match rpassword::prompt_password(confirmation_prompt) {
+                Ok(resp) => resp,
+                Err(error) => {
+                    error!("Failed to read input password:\n {error}");
+                    return Err(());
+                }
+            }
+        } else {
+            match read_password_from_stdin(confirmation_prompt) {
+                Ok(resp) => resp,
+                Err(_error) => {
+                    return Err(());
+                }
+            }
         };
 
         // Remove special characters from the confirmation password
-        let mut password_again = password_again.trim().to_string();
+        let mut password_again = trim_password(password_again);
 
-        // Make sure the passwords match
-        if password != password_again {
+        // Make sure the passwords match, then flush the memory that holds our confirmation password with 0s for some extra security
+        let passwords_match = password == password_again;
+        password_again.zeroize();
+        if !passwords_match {
+            password.zeroize();
             error!("Passwords don't match");
             return Err(());
         };
-
-        // Flush the memory that holds our confirmation password with 0s for some extra security
-        password_again.zeroize();
     }
 
     // Return the password
-    Ok(password.to_string())
+    Ok(password)
+}
+
+// Removes leading and trailing whitespace (I.E. the newline) from a password, and flushes the untrimmed password with 0s
+fn trim_password(mut password: String) -> String {
+    let trimmed_password = password.trim().to_string();
+    password.zeroize();
+    trimmed_password
+}
+
+// Prompts the user for their recovery key on the terminal, without echoing it
+// Note: If there's no terminal (I.E. in a script), the recovery key is read from stdin instead, unless stdin is being used for the input file
+fn prompt_user_for_recovery_key(stdin_is_input: bool) -> Result<String, ()> {
+    let prompt = "Input the recovery key\n> ";
+    match rpassword::prompt_password(prompt) {
+        Ok(resp) => Ok(resp),
+        Err(error) => {
+            if stdin_is_input {
+                error!("Failed to read the recovery key from the terminal (stdin is being used for the input file):\n {error}");
+                return Err(());
+            }
+            warn!("There's no terminal to read the recovery key from, so it will be read from stdin");
+            read_password_from_stdin(prompt)
+        }
+    }
+}
+
+// Shows a recovery key that was just created. It's written to stderr, so it doesn't end up in the output if it's stdout
+// Note: The recovery key is only stored in encrypted form, so this is the only time it can be shown
+fn show_recovery_key(recovery_key: Option<String>) {
+    if let Some(mut recovery_key) = recovery_key {
+        eprintln!("Recovery key (write it down and keep it somewhere safe, it can decrypt the file without the password and won't be shown again):");
+        eprintln!("{recovery_key}");
+        recovery_key.zeroize();
+    }
+}
+
+// Writes the shares that were just split (if there are any) next to the encrypted file, as `<file>.share-<index>`
+// Note: They're written before the file is encrypted, so a file can never be left behind without its shares
+fn write_shares(file: &str, credentials: &Credentials) -> Vec<PathBuf> {
+    if credentials.shares.is_empty() {
+        return Vec::new();
+    }
+    if file == STANDARD_STREAM {
+        exit_with_error("Failed to write the shares", Error::Crypto("The share files are named after the output file, so it can't be stdout".to_string()));
+    }
+
+    match write_share_files(file, &credentials.shares) {
+        Ok(resp) => {
+            for share_file in &resp {
+                info!("Wrote the share file '{}'. Give it to one of the custodians", share_file.display());
+            }
+            resp
+        },
+        Err(error) => {
+            exit_with_error("Failed to write the shares", error);
+        }
+    }
+}
+
+// Removes the share files written by `write_shares`, when the file couldn't be encrypted
+fn remove_share_files(share_files: &[PathBuf]) {
+    for share_file in share_files {
+        let _ = std::fs::remove_file(share_file);
+    }
+}
+
+// Reads a line from stdin. The prompt is written to stderr, so it doesn't end up in the output if it's stdout
+// Note: The line is returned untrimmed, so the caller must flush it with 0s (see `trim_password`)
+fn read_password_from_stdin(prompt: &str) -> Result<String, ()> {
+    // Reserve enough space up front, so that the buffer isn't moved (leaving a copy of the password behind) while the line is read
+    let mut password = String::with_capacity(1024);
+
+    eprint!("{prompt}");
+    match std::io::stdin().lock().read_line(&mut password) {
+        Ok(_resp) => Ok(password),
+        Err(error) => {
+            password.zeroize();
+            error!("Failed to read input password:\n {error}");
+            Err(())
+        }
+    }
 }
@@ -16,12 +16,38 @@ along with this program.  If not, see https://www.gnu.org/licenses/gpl-3.0.html.
 */
 
 
-use std::{fs::{OpenOptions, remove_file, remove_dir_all}, path::Path};
-use log::error;
-use tar::{Builder, Archive};
+use std::{collections::HashMap, fs::{OpenOptions, remove_file}, io::{BufRead, BufReader, BufWriter, Read, Write}, path::{Component, Path, PathBuf}};
+use log::warn;
+use tar::{Builder, Archive, Entry, EntryType};
+use crate::modules::{error::{Error, Result}, compression::{detect_compression, CompressingWriter, DecompressingReader}, encryption::{Credentials, DecryptingReader, EncryptingWriter, EncryptionOptions}, header::Compression, key_slots::explain_modified_header};
 
 
-pub fn pack<T: AsRef<Path>>(input_folder: T, output_tarball: T) -> Result<(), ()> {
+// The number of symlinks that a symlink's target may go through before we give up on following it (the same as Linux)
+const MAX_SYMLINK_DEPTH: usize = 40;
+
+
+/// How a directory is packed into a tarball
+#[derive(Clone, Debug, Default)]
+pub struct PackOptions {
+    /// The directory that everything is stored under in the tarball (I.E. `project` stores `src/main.rs` as `project/src/main.rs`).
+    /// An empty path (or `.`) stores everything at the top of the tarball. If it's `None`, the name of the input folder is used
+    pub root: Option<PathBuf>
+}
+
+/// How a tarball is unpacked
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UnpackOptions {
+    /// Rejects every entry that could end up outside the output folder: absolute paths, paths containing `..`, paths that go through a symlink,
+    /// symlinks and hard links that point outside the output folder, device nodes and FIFOs. Every rejected entry is logged, and the rest of the tarball is still unpacked
+    pub hardened: bool,
+    /// Removes this many leading directories from the path of every entry (I.E. 1 unpacks `project/src/main.rs` as `src/main.rs`), like `tar --strip-components`.
+    /// Entries with no path left are skipped
+    pub strip_components: usize
+}
+
+
+// Packs a directory into a tarball, which is compressed with the given algorithm
+pub fn pack<T: AsRef<Path>>(input_folder: T, output_tarball: T, compression: Compression, pack_options: PackOptions) -> Result<()> {
     // Try to open an instance of the output_tarball
     let output_file_options = OpenOptions::new()
     .write(true)
@@ -36,40 +62,118 @@ pub fn pack<T: AsRef<Path>>(input_folder: T, output_tarball: T) -> Result<(), ()
             resp
         },
         Err(error) => {
-            error!("Failed to create the output archive file:\n {error}");
-            return Err(());
+            return Err(Error::create(output_tarball.as_ref(), error));
+        }
+    };
+
+    // Pack the input_folder into the output_tarball
+    match pack_to(input_folder, BufWriter::new(output_file), compression, pack_options) {
+        Ok(_resp) => {},
+        Err(error) => {
+            // let _ = delete_file(output_tarball);
+            return Err(error);
+        }
+    };
+
+    // Return the success!
+    Ok(())
+}
+
+// Packs a directory into a tarball, which is compressed with the given algorithm and written to the given writer (I.E. stdout)
+// Returns the writer once everything was written to it
+pub fn pack_to<T: AsRef<Path>, W: Write>(input_folder: T, writer: W, compression: Compression, pack_options: PackOptions) -> Result<W> {
+    // Everything written by the archive builder is compressed (if requested) before it reaches the writer
+    let compressing_writer = match CompressingWriter::new(writer, compression) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(Error::io("Failed to initialize the compressor", error));
+        }
+    };
+
+    // Pack the input_folder into the tarball
+    let compressing_writer = match pack_into(&input_folder, compressing_writer, pack_options) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(error);
+        }
+    };
+
+    // Finish compressing, and make sure that everything was written to the writer
+    let mut output_writer = match compressing_writer.finish() {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(Error::io("Failed to finish compressing the tar archive", error));
+        }
+    };
+    match output_writer.flush() {
+        Ok(_resp) => Ok(output_writer),
+        Err(error) => {
+            Err(Error::io("Failed to finish writing to the tar archive", error))
+        }
+    }
+}
+
+// Packs a directory (and its contents) into a tarball, which is written to the given writer
+// The paths in the tarball are relative to the input_folder, under the root from the pack_options
+// Returns the writer once the tarball has been finalized
+pub fn pack_into<T: AsRef<Path>, W: Write>(input_folder: T, writer: W, pack_options: PackOptions) -> Result<W> {
+    // Work out where the contents of the input_folder go in the tarball
+    let root = match archive_root(input_folder.as_ref(), pack_options) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(error);
         }
     };
 
     // Create an instance of the archive builder
-    let mut tar_builder = Builder::new(output_file);
+    let mut tar_builder = Builder::new(writer);
     
-    // Use the archive builder to clone the input_folder into the output_tarball
-    match tar_builder.append_dir_all(&input_folder, &input_folder) {
+    // Use the archive builder to clone the input_folder into the tarball
+    match tar_builder.append_dir_all(&root, &input_folder) {
         Ok(_) => {},
         Err(error) => {
-            // let _ = delete_file(output_tarball);
-            error!("Failed to create the tar archive:\n {error}");
-            return Err(());
+            return Err(Error::io("Failed to create the tar archive", error));
         }
     };
 
-    // Finalize the output_tarball, appending the termination bytes to the end of the output_tarball
+    // Finalize the tarball, appending the termination bytes to the end of it
     match tar_builder.into_inner() {
-        Ok(_resp) => {},
+        Ok(resp) => Ok(resp),
         Err(error) => {
-            // let _ = delete_file(output_tarball);
-            error!("Failed to finish writing to the tar archive:\n {error}");
-            return Err(());
+            Err(Error::io("Failed to finish writing to the tar archive", error))
         }
     }
+}
 
-    // Return the success!
-    Ok(())
+// Returns the root directory of the tarball: the one from the pack_options, or the name of the input_folder
+fn archive_root(input_folder: &Path, pack_options: PackOptions) -> Result<PathBuf> {
+    let root = match pack_options.root {
+        Some(resp) => resp,
+        None => {
+            // Use the real name of the input_folder, even if it was given as `.` or `..`
+            return match input_folder.canonicalize() {
+                Ok(resp) => Ok(resp.file_name().map(PathBuf::from).unwrap_or_default()),
+                Err(error) => Err(Error::io(format!("Failed to open the input directory '{}'", input_folder.display()), error))
+            };
+        }
+    };
+
+    // The root must stay inside the directory that the tarball is unpacked in
+    let mut relative_root = PathBuf::new();
+    for component in root.components() {
+        match component {
+            Component::Normal(name) => relative_root.push(name),
+            Component::CurDir => {},
+            _ => {
+                return Err(Error::UnsupportedFormat(format!("The root of the tarball must be a relative path without '..', not '{}'", root.display())));
+            }
+        }
+    }
+    Ok(relative_root)
 }
 
 // Unpacks a tarball's contents into the specified output_folder
-pub fn unpack<T: AsRef<Path>>(input_tarball: T, output_folder: T) -> Result<(), ()> {
+pub fn unpack<T: AsRef<Path>>(input_tarball: T, output_folder: T, unpack_options: UnpackOptions) -> Result<()> {
     // Try to open the input_tarball
     let input_tarball_options = OpenOptions::new()
     .write(false)
@@ -82,21 +186,15 @@ pub fn unpack<T: AsRef<Path>>(input_tarball: T, output_folder: T) -> Result<(),
             resp
         },
         Err(error) => {
-            error!("Failed to open the input tarball:\n {error}");
-            return Err(());
+            return Err(Error::io("Failed to open the input tarball", error));
         }
     };
 
-    // Create an instance of the archiver
-    let mut tar_unpacker = Archive::new(input_tarball);
-
     // Try to unpack the input_tarball into the output_folder
-    match tar_unpacker.unpack(&output_folder) {
+    match unpack_from(input_tarball, &output_folder, unpack_options) {
         Ok(_resp) => {},
         Err(error) => {
-            // let _ = delete_directory_recursively(output_folder);
-            error!("Failed to unpack the input tarball:\n {error}");
-            return Err(());
+            return Err(error);
         },
     };
 
@@ -104,32 +202,672 @@ pub fn unpack<T: AsRef<Path>>(input_tarball: T, output_folder: T) -> Result<(),
     Ok(())
 }
 
-// Used to delete a file. Useful if we failed to create a tarball and want to clean up the mess
-#[allow(dead_code)]
-pub fn delete_file<T: AsRef<Path>>(input_file: T) -> Result<(), ()> {
-    // Try to delete the input_file
-    match remove_file(&input_file) {
-        Ok(_resp) => {
-            Ok(())
+// Unpacks a tarball, which is read from the given reader, into the specified output_folder
+// Compressed tarballs (gzip, zstd, xz and snappy) are detected from their magic bytes and decompressed automatically
+pub fn unpack_from<R: Read, T: AsRef<Path>>(reader: R, output_folder: T, unpack_options: UnpackOptions) -> Result<()> {
+    // Look at the first few bytes of the tarball to work out if (and how) it was compressed
+    let mut reader = BufReader::new(reader);
+    let compression = match reader.fill_buf() {
+        Ok(resp) => detect_compression(resp),
+        Err(error) => {
+            return Err(Error::io("Failed to read the tarball", error));
+        }
+    };
+    let decompressing_reader = match DecompressingReader::new(reader, compression) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(Error::io("Failed to initialize the decompressor", error));
+        }
+    };
+
+    // Create an instance of the archiver
+    let mut tar_unpacker = Archive::new(decompressing_reader);
+
+    // Unpack tarballs we don't trust (or that need their paths changed) one entry at a time
+    if unpack_options.hardened || unpack_options.strip_components > 0 {
+        return unpack_entries(&mut tar_unpacker, output_folder.as_ref(), unpack_options);
+    }
+
+    // Try to unpack the tarball into the output_folder
+    match tar_unpacker.unpack(&output_folder) {
+        Ok(_resp) => Ok(()),
+        Err(error) => {
+            Err(Error::io("Failed to unpack the tarball", error))
+        },
+    }
+}
+
+// Unpacks a tarball one entry at a time, stripping the first `strip_components` directories from every path
+// In hardened mode, every entry that could end up outside the output_folder is skipped (and logged), and symlinks are created after everything else,
+// once every symlink in the tarball is known, so one symlink can't be used to redirect another
+fn unpack_entries<R: Read>(tar_unpacker: &mut Archive<R>, output_folder: &Path, unpack_options: UnpackOptions) -> Result<()> {
+    // Create the output_folder, and use its canonical path so that the symlinks on disk are found
+    if output_folder.symlink_metadata().is_err() {
+        match std::fs::create_dir_all(output_folder) {
+            Ok(_resp) => {},
+            Err(error) => {
+                return Err(Error::create(output_folder, error));
+            }
+        };
+    }
+    let output_folder = match output_folder.canonicalize() {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(Error::io(format!("Failed to open the output directory '{}'", output_folder.display()), error));
+        }
+    };
+
+    let entries = match tar_unpacker.entries() {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(Error::io("Failed to read the tarball", error));
+        }
+    };
+
+    // Directories are unpacked last (like tar::Archive::unpack does), so their permissions can't stop their contents from being unpacked
+    let mut directories = Vec::new();
+    let mut symlinks = Vec::new();
+    let mut symlink_targets: HashMap<PathBuf, PathBuf> = HashMap::new();
+    let mut num_rejected = 0;
+    for entry in entries {
+        let mut entry = match entry {
+            Ok(resp) => resp,
+            Err(error) => {
+                return Err(Error::io("Failed to read the tarball", error));
+            }
+        };
+        let entry_path = match entry.path() {
+            Ok(resp) => resp.into_owned(),
+            Err(error) => {
+                return Err(Error::io("Failed to read the path of an entry in the tarball", error));
+            }
+        };
+
+        // Make sure that the entry stays inside the output_folder
+        let entry_type = entry.header().entry_type();
+        let checked = match check_entry_path(&entry_path, &symlink_targets, &output_folder, unpack_options) {
+            Ok(None) => {
+                // Everything in the path was stripped, so there's nothing to unpack
+                continue;
+            },
+            Ok(Some(_path)) if unpack_options.hardened && (entry_type.is_character_special() || entry_type.is_block_special()) => Err("it's a device node".to_string()),
+            Ok(Some(_path)) if unpack_options.hardened && entry_type.is_fifo() => Err("it's a FIFO".to_string()),
+            Ok(Some(path)) if entry_type.is_hard_link() => match entry.link_name() {
+                Ok(Some(link_name)) => check_hard_link(&link_name, &symlink_targets, &output_folder, unpack_options).map(|target| (path, Some(target))),
+                _ => Err("the hard link has no target".to_string())
+            },
+            Ok(Some(path)) if entry_type.is_symlink() && unpack_options.hardened => match entry.link_name() {
+                Ok(Some(link_name)) => {
+                    symlink_targets.insert(path.clone(), link_name.into_owned());
+                    Ok((path, None))
+                },
+                _ => Err("the symlink has no target".to_string())
+            },
+            Ok(Some(path)) => Ok((path, None)),
+            Err(reason) => Err(reason)
+        };
+        let (path, hard_link_target) = match checked {
+            Ok(resp) => resp,
+            Err(reason) => {
+                warn!("Rejected '{}' from the tarball, because {reason}", entry_path.display());
+                num_rejected += 1;
+                continue;
+            }
+        };
+
+        if entry_type == EntryType::Directory {
+            directories.push((entry, path));
+        } else if entry_type.is_symlink() && unpack_options.hardened {
+            symlinks.push((entry, path));
+        } else {
+            match unpack_entry(&mut entry, &output_folder, &path, hard_link_target.as_deref()) {
+                Ok(_resp) => {},
+                Err(error) => {
+                    return Err(error);
+                }
+            };
+        }
+    }
+
+    // Now that every symlink is known, make sure that each of them points inside the output_folder, even when it goes through the others
+    for (mut symlink, path) in symlinks {
+        let checked = match check_entry_path(&path, &symlink_targets, &output_folder, UnpackOptions { strip_components: 0, ..unpack_options }) {
+            Ok(_path) if output_folder.join(&path).symlink_metadata().map(|metadata| metadata.is_dir()).unwrap_or(false) => Err(format!("something else was already unpacked at '{}'", path.display())),
+            Ok(_path) => match symlink_targets.get(&path) {
+                Some(target) if resolve_symlink(path.parent().unwrap_or(Path::new("")), target, &symlink_targets, &output_folder, 0).is_some() => Ok(()),
+                _ => Err("the symlink leads outside of the output directory (or it loops)".to_string())
+            },
+            Err(reason) => Err(reason)
+        };
+        match checked {
+            Ok(_resp) => {},
+            Err(reason) => {
+                warn!("Rejected '{}' from the tarball, because {reason}", path.display());
+                num_rejected += 1;
+                continue;
+            }
+        };
+
+        match unpack_entry(&mut symlink, &output_folder, &path, None) {
+            Ok(_resp) => {},
+            Err(error) => {
+                return Err(error);
+            }
+        };
+    }
+
+    // Apply the directories, deepest first (see tar::Archive::unpack), checking them again now that every symlink is known
+    directories.sort_by(|a, b| b.1.cmp(&a.1));
+    for (mut directory, path) in directories {
+        match check_entry_path(&path, &symlink_targets, &output_folder, UnpackOptions { strip_components: 0, ..unpack_options }) {
+            Ok(_path) => {},
+            Err(reason) => {
+                warn!("Rejected '{}' from the tarball, because {reason}", path.display());
+                num_rejected += 1;
+                continue;
+            }
+        };
+
+        match unpack_entry(&mut directory, &output_folder, &path, None) {
+            Ok(_resp) => {},
+            Err(error) => {
+                return Err(error);
+            }
+        };
+    }
+
+    match num_rejected {
+        0 => Ok(()),
+        1 => Err(Error::UnsupportedFormat("1 unsafe entry in the tarball was rejected (the rest of it was unpacked)".to_string())),
+        _ => Err(Error::UnsupportedFormat(format!("{num_rejected} unsafe entries in the tarball were rejected (the rest of it was unpacked)")))
+    }
+}
+
+// Unpacks an entry at `path` inside the output_folder. Hard links are created here, because tar would look for their target relative to the current directory
+fn unpack_entry<R: Read>(entry: &mut Entry<R>, output_folder: &Path, path: &Path, hard_link_target: Option<&Path>) -> Result<()> {
+    let destination = output_folder.join(path);
+
+    // Create the parent directories, and (like tar::Entry::unpack_in) refuse to write through a symlink that leads outside of the output_folder
+    let parent = destination.parent().unwrap_or(output_folder);
+    let canonical_parent = match std::fs::create_dir_all(parent).and_then(|_| parent.canonicalize()) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(Error::io(format!("Failed to create '{}'", parent.display()), error));
+        }
+    };
+    if !canonical_parent.starts_with(output_folder) {
+        return Err(Error::UnsupportedFormat(format!("'{}' would be unpacked outside of the output directory, through a symlink", path.display())));
+    }
+
+    let result = match hard_link_target {
+        Some(target) => {
+            // The target mustn't lead outside of the output_folder through a symlink either
+            let source = output_folder.join(target);
+            if !source.canonicalize().map(|canonical_source| canonical_source.starts_with(output_folder)).unwrap_or(true) {
+                return Err(Error::UnsupportedFormat(format!("The hard link '{}' points outside of the output directory, through a symlink", path.display())));
+            }
+            let _ = remove_file(&destination);
+            std::fs::hard_link(source, &destination)
+        },
+        None => entry.unpack(&destination).map(|_unpacked| ())
+    };
+    match result {
+        Ok(_resp) => Ok(()),
+        Err(error) => Err(Error::io(format!("Failed to unpack '{}'", path.display()), error))
+    }
+}
+
+// Turns the path of an entry into a path inside the output_folder, or says why it isn't safe to unpack
+// Returns `None` if every part of the path was stripped
+fn check_entry_path(entry_path: &Path, symlink_targets: &HashMap<PathBuf, PathBuf>, output_folder: &Path, unpack_options: UnpackOptions) -> std::result::Result<Option<PathBuf>, String> {
+    // Leading slashes are only ignored when we trust the tarball (like tar::Entry::unpack_in), but '..' is never allowed
+    let mut path = PathBuf::new();
+    let mut num_stripped = 0;
+    for component in entry_path.components() {
+        match component {
+            Component::Prefix(_) | Component::RootDir if unpack_options.hardened => {
+                return Err("its path is absolute".to_string());
+            },
+            Component::ParentDir => {
+                return Err("its path contains '..'".to_string());
+            },
+            Component::Prefix(_) | Component::RootDir | Component::CurDir => {},
+            Component::Normal(_name) if num_stripped < unpack_options.strip_components => num_stripped += 1,
+            Component::Normal(name) => path.push(name)
+        }
+    }
+    if path.as_os_str().is_empty() {
+        return Ok(None);
+    }
+
+    // When hardened, nothing may be unpacked through a symlink, because it could lead anywhere
+    let mut parent = path.parent();
+    while let Some(directory) = parent.filter(|directory| unpack_options.hardened && !directory.as_os_str().is_empty()) {
+        if symlink_targets.contains_key(directory) || is_symlink(&output_folder.join(directory)) {
+            return Err(format!("its path goes through the symlink '{}'", directory.display()));
+        }
+        parent = directory.parent();
+    }
+
+    Ok(Some(path))
+}
+
+// Makes sure that a hard link points inside the output_folder (with the same components stripped as every other path)
+// When hardened, it must also point at something that was already unpacked, and that isn't a symlink
+fn check_hard_link(link_name: &Path, symlink_targets: &HashMap<PathBuf, PathBuf>, output_folder: &Path, unpack_options: UnpackOptions) -> std::result::Result<PathBuf, String> {
+    let target = match check_entry_path(link_name, symlink_targets, output_folder, unpack_options) {
+        Ok(Some(resp)) => resp,
+        Ok(None) => {
+            return Err("the target of the hard link was stripped".to_string());
+        },
+        Err(reason) => {
+            return Err(format!("the target of the hard link is unsafe ({reason})"));
+        }
+    };
+    if unpack_options.hardened && (symlink_targets.contains_key(&target) || is_symlink(&output_folder.join(&target))) {
+        return Err("the hard link points at a symlink".to_string());
+    }
+    if unpack_options.hardened && output_folder.join(&target).symlink_metadata().is_err() {
+        return Err("the hard link points at something that wasn't unpacked before it".to_string());
+    }
+    Ok(target)
+}
+
+// Follows the target of a symlink in `directory` the way the operating system would, through the other symlinks in the tarball
+// Returns where it leads (relative to the output_folder), or `None` if it leaves the output_folder, goes through a symlink that was already on disk, or loops
+fn resolve_symlink(directory: &Path, target: &Path, symlink_targets: &HashMap<PathBuf, PathBuf>, output_folder: &Path, depth: usize) -> Option<PathBuf> {
+    if depth > MAX_SYMLINK_DEPTH {
+        return None;
+    }
+
+    let mut resolved = directory.to_path_buf();
+    for component in target.components() {
+        match component {
+            Component::Prefix(_) | Component::RootDir => {
+                return None;
+            },
+            Component::ParentDir => {
+                if !resolved.pop() {
+                    return None;
+                }
+            },
+            Component::CurDir => {},
+            Component::Normal(name) => {
+                resolved.push(name);
+                if let Some(next_target) = symlink_targets.get(&resolved) {
+                    resolved.pop();
+                    resolved = match resolve_symlink(&resolved, next_target, symlink_targets, output_folder, depth + 1) {
+                        Some(resp) => resp,
+                        None => {
+                            return None;
+                        }
+                    };
+                } else if is_symlink(&output_folder.join(&resolved)) {
+                    return None;
+                }
+            }
+        }
+    }
+
+    Some(resolved)
+}
+
+// Returns whether there's a symlink at the path on disk
+fn is_symlink(path: &Path) -> bool {
+    path.symlink_metadata().map(|metadata| metadata.file_type().is_symlink()).unwrap_or(false)
+}
+
+// Packs a directory into a tarball and encrypts it in one pass, so the unencrypted tarball is never written to disk
+pub fn seal<T: AsRef<Path>, C: Into<Credentials>>(input_folder: T, output_file: T, credentials: C, encryption_options: EncryptionOptions, pack_options: PackOptions) -> Result<()> {
+    // Try to open an instance of the output_file
+    let output_file_options = OpenOptions::new()
+    .write(true)
+    .append(false)
+    .read(false)
+    .create_new(true)
+    .open(&output_file);
+
+    // Ensure that we created the output_file safely
+    let output = match output_file_options {
+        Ok(resp) => {
+            resp
         },
         Err(error) => {
-            error!("Failed to delete file at '{:?}':\n {error}", input_file.as_ref());
-            Err(())
+            return Err(Error::create(output_file.as_ref(), error));
+        }
+    };
+
+    // Everything written by the archive builder is encrypted before it reaches the output_file
+    let encrypting_writer = match EncryptingWriter::new(BufWriter::new(output), credentials, encryption_options) {
+        Ok(resp) => resp,
+        Err(error) => {
+            let _ = delete_file(&output_file);
+            return Err(error);
+        }
+    };
+
+    // Pack the input_folder, then encrypt the final block
+    let encrypting_writer = match pack_into(&input_folder, encrypting_writer, pack_options) {
+        Ok(resp) => resp,
+        Err(error) => {
+            let _ = delete_file(&output_file);
+            return Err(error);
+        }
+    };
+    match encrypting_writer.finish() {
+        Ok(_resp) => {},
+        Err(error) => {
+            let _ = delete_file(&output_file);
+            return Err(error);
+        }
+    };
+
+    // Return the success!
+    Ok(())
+}
+
+// Decrypts a sealed archive and unpacks it into the specified output_folder in one pass
+// Note: Every block is authenticated before it's unpacked, but if decryption fails part way through, the output_folder may contain some of the archive
+pub fn open<T: AsRef<Path>, C: Into<Credentials>>(input_file: T, output_folder: T, credentials: C, unpack_options: UnpackOptions) -> Result<()> {
+    // Try to open the input_file
+    let input_file_options = OpenOptions::new()
+    .write(false)
+    .read(true)
+    .open(&input_file);
+
+    // Make sure that we safely opened the input_file
+    let input = match input_file_options {
+        Ok(resp) => {
+            resp
         },
+        Err(error) => {
+            return Err(Error::io("Failed to open the input file", error));
+        }
+    };
+
+    // The password is checked here, before anything is unpacked
+    let mut decrypting_reader = match DecryptingReader::new(BufReader::new(input), credentials) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(explain_modified_header(&input_file, error));
+        }
+    };
+
+    // Unpack the decrypted tarball into the output_folder
+    match unpack_from(&mut decrypting_reader, output_folder, unpack_options) {
+        Ok(_resp) => {},
+        Err(error) => {
+            return Err(error);
+        }
+    };
+
+    // The unpacker stops at the end of the tarball, so read the rest of the file to make sure that the final block is there
+    match std::io::copy(&mut decrypting_reader, &mut std::io::sink()) {
+        Ok(_resp) => Ok(()),
+        Err(error) => {
+            Err(Error::io("Failed to decrypt the end of the sealed tarball", error))
+        }
     }
 }
 
-// Used to delete a directory. Useful if we failed while unpacking a tarball and want to clean up the mess
-#[allow(dead_code)]
-pub fn delete_directory_recursively<T: AsRef<Path>>(input_directory: T) -> Result<(), ()> {
-    // Try to recursively delete the input_directory
-    match remove_dir_all(&input_directory) {
+// Used to delete a file. Useful if we failed to create a tarball and want to clean up the mess
+pub(crate) fn delete_file<T: AsRef<Path>>(input_file: T) -> Result<()> {
+    // Try to delete the input_file
+    match remove_file(&input_file) {
         Ok(_resp) => {
             Ok(())
         },
         Err(error) => {
-            error!("Failed to delete directory at '{:?}':\n {error}", input_directory.as_ref());
-            Err(())
+            Err(Error::io(format!("Failed to delete file at '{:?}'", input_file.as_ref()), error))
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::remove_dir_all;
+    use tar::Header;
+
+    // An entry of a tarball built by `tarball`
+    enum TestEntry<'a> {
+        File(&'a str),
+        Directory(&'a str),
+        Symlink(&'a str, &'a str),
+        HardLink(&'a str, &'a str),
+        Device(&'a str),
+        Fifo(&'a str)
+    }
+
+    // A new directory for a test to unpack in, which is removed when the test ends
+    struct TestFolder(PathBuf);
+
+    impl TestFolder {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("arch-crypt-test-{}-{name}", std::process::id()));
+            let _ = remove_dir_all(&path);
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path.canonicalize().unwrap())
+        }
+
+        fn output(&self) -> PathBuf {
+            self.0.join("output")
+        }
+    }
+
+    impl Drop for TestFolder {
+        fn drop(&mut self) {
+            let _ = remove_dir_all(&self.0);
+        }
+    }
+
+    // Builds a tarball in memory. The paths are written into the raw headers, because tar::Header::set_path refuses the unsafe ones
+    fn tarball(entries: &[TestEntry]) -> Vec<u8> {
+        let mut tar_builder = Builder::new(Vec::new());
+        for entry in entries {
+            let (path, entry_type, link_name, contents): (&str, EntryType, Option<&str>, &[u8]) = match entry {
+                TestEntry::File(path) => (path, EntryType::Regular, None, b"contents"),
+                TestEntry::Directory(path) => (path, EntryType::Directory, None, b""),
+                TestEntry::Symlink(path, target) => (path, EntryType::Symlink, Some(target), b""),
+                TestEntry::HardLink(path, target) => (path, EntryType::Link, Some(target), b""),
+                TestEntry::Device(path) => (path, EntryType::Char, None, b""),
+                TestEntry::Fifo(path) => (path, EntryType::Fifo, None, b"")
+            };
+            let mut header = Header::new_gnu();
+            header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+            if let Some(link_name) = link_name {
+                header.as_old_mut().linkname[..link_name.len()].copy_from_slice(link_name.as_bytes());
+            }
+            header.set_entry_type(entry_type);
+            header.set_mode(if entry_type == EntryType::Directory { 0o755 } else { 0o644 });
+            header.set_size(contents.len() as u64);
+            header.set_cksum();
+            tar_builder.append(&header, contents).unwrap();
+        }
+        tar_builder.into_inner().unwrap()
+    }
+
+    // Unpacks a tarball into the output folder of the test
+    fn unpack_tarball(test_folder: &TestFolder, entries: &[TestEntry], unpack_options: UnpackOptions) -> Result<()> {
+        unpack_from(tarball(entries).as_slice(), test_folder.output(), unpack_options)
+    }
+
+    // Returns how many entries were rejected, according to the error
+    fn num_rejected(result: &Result<()>) -> usize {
+        match result {
+            Ok(_resp) => 0,
+            Err(Error::UnsupportedFormat(message)) => message.split(' ').next().unwrap().parse().unwrap(),
+            Err(error) => panic!("unexpected error: {error}")
+        }
+    }
+
+    const HARDENED: UnpackOptions = UnpackOptions { hardened: true, strip_components: 0 };
+
+    #[test]
+    fn safe_tarballs_are_unpacked_completely() {
+        let test_folder = TestFolder::new("safe");
+        let result = unpack_tarball(&test_folder, &[
+            TestEntry::Directory("project/"),
+            TestEntry::File("project/README"),
+            TestEntry::File("./project/src/main.rs"),
+            TestEntry::Symlink("project/link", "src/main.rs"),
+            TestEntry::Symlink("project/src/up", "../README"),
+            TestEntry::HardLink("project/copy", "project/README")
+        ], HARDENED);
+        assert_eq!(num_rejected(&result), 0);
+        let output = test_folder.output();
+        assert_eq!(std::fs::read(output.join("project/link")).unwrap(), b"contents");
+        assert_eq!(std::fs::read(output.join("project/src/up")).unwrap(), b"contents");
+        assert_eq!(std::fs::read(output.join("project/copy")).unwrap(), b"contents");
+    }
+
+    #[test]
+    fn absolute_paths_and_parent_directories_are_rejected() {
+        let test_folder = TestFolder::new("paths");
+        let absolute = test_folder.0.join("absolute");
+        let result = unpack_tarball(&test_folder, &[
+            TestEntry::File(absolute.to_str().unwrap()),
+            TestEntry::File("../parent"),
+            TestEntry::File("a/../../parent-through-a"),
+            TestEntry::File("safe")
+        ], HARDENED);
+        assert_eq!(num_rejected(&result), 3);
+        assert!(!absolute.exists());
+        assert!(!test_folder.0.join("parent").exists());
+        assert!(!test_folder.0.join("parent-through-a").exists());
+        assert!(test_folder.output().join("safe").exists());
+    }
+
+    #[test]
+    fn paths_through_symlinks_are_rejected() {
+        let test_folder = TestFolder::new("through");
+        let result = unpack_tarball(&test_folder, &[
+            TestEntry::Symlink("inside", "."),
+            TestEntry::File("inside/file"),
+            TestEntry::Symlink("etc", "/etc"),
+            TestEntry::File("etc/passwd")
+        ], HARDENED);
+        assert_eq!(num_rejected(&result), 3);
+        assert!(!test_folder.output().join("file").exists());
+        assert!(test_folder.output().join("inside").symlink_metadata().is_ok());
+    }
+
+    #[test]
+    fn symlinks_leading_outside_are_rejected() {
+        let test_folder = TestFolder::new("symlinks");
+        let result = unpack_tarball(&test_folder, &[
+            TestEntry::Symlink("absolute", "/etc/passwd"),
+            TestEntry::Symlink("up", "../outside"),
+            TestEntry::Symlink("deep/up", "../../outside"),
+            // A chain of symlinks that only leads outside once they're combined
+            TestEntry::Symlink("a", "b/.."),
+            TestEntry::Symlink("b", "c/.."),
+            TestEntry::Symlink("c", ".."),
+            TestEntry::Symlink("loop1", "loop2"),
+            TestEntry::Symlink("loop2", "loop1"),
+            TestEntry::Symlink("fine", "deep/../absolute-free")
+        ], HARDENED);
+        assert_eq!(num_rejected(&result), 8);
+        assert!(test_folder.output().join("fine").symlink_metadata().is_ok());
+        assert!(test_folder.output().join("absolute").symlink_metadata().is_err());
+    }
+
+    #[test]
+    fn hard_links_leading_outside_are_rejected() {
+        let test_folder = TestFolder::new("hard-links");
+        let result = unpack_tarball(&test_folder, &[
+            TestEntry::File("file"),
+            TestEntry::HardLink("absolute", "/etc/passwd"),
+            TestEntry::HardLink("up", "../outside"),
+            TestEntry::HardLink("missing", "not-unpacked-yet"),
+            TestEntry::Symlink("symlink", "file"),
+            TestEntry::HardLink("through-symlink", "symlink"),
+            TestEntry::HardLink("fine", "file")
+        ], HARDENED);
+        assert_eq!(num_rejected(&result), 4);
+        assert!(test_folder.output().join("absolute").symlink_metadata().is_err());
+        assert_eq!(std::fs::read(test_folder.output().join("fine")).unwrap(), b"contents");
+    }
+
+    #[test]
+    fn device_nodes_and_fifos_are_rejected() {
+        let test_folder = TestFolder::new("special");
+        let result = unpack_tarball(&test_folder, &[
+            TestEntry::Device("null"),
+            TestEntry::Fifo("pipe"),
+            TestEntry::File("file")
+        ], HARDENED);
+        assert_eq!(num_rejected(&result), 2);
+        assert!(test_folder.output().join("null").symlink_metadata().is_err());
+        assert!(test_folder.output().join("pipe").symlink_metadata().is_err());
+        assert!(test_folder.output().join("file").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_already_on_disk_are_not_followed() {
+        let test_folder = TestFolder::new("on-disk");
+        std::fs::create_dir_all(test_folder.output()).unwrap();
+        std::os::unix::fs::symlink(&test_folder.0, test_folder.output().join("escape")).unwrap();
+        let result = unpack_tarball(&test_folder, &[
+            TestEntry::File("escape/file"),
+            TestEntry::Symlink("link", "escape")
+        ], HARDENED);
+        assert_eq!(num_rejected(&result), 2);
+        assert!(!test_folder.0.join("file").exists());
+    }
+
+    #[test]
+    fn leading_components_are_stripped() {
+        let test_folder = TestFolder::new("strip");
+        let result = unpack_tarball(&test_folder, &[
+            TestEntry::Directory("project/"),
+            TestEntry::File("project"),
+            TestEntry::File("project/src/main.rs"),
+            TestEntry::HardLink("project/copy", "project/src/main.rs"),
+            TestEntry::Symlink("project/link", "src/main.rs")
+        ], UnpackOptions { hardened: true, strip_components: 1 });
+        assert_eq!(num_rejected(&result), 0);
+        let output = test_folder.output();
+        assert!(output.join("src/main.rs").is_file());
+        assert_eq!(std::fs::read(output.join("copy")).unwrap(), b"contents");
+        assert_eq!(std::fs::read(output.join("link")).unwrap(), b"contents");
+        assert!(output.join("project").symlink_metadata().is_err());
+    }
+
+    #[test]
+    fn symlinks_are_resolved_through_each_other() {
+        let output_folder = Path::new("/nonexistent/arch-crypt-test");
+        let symlink_targets: HashMap<PathBuf, PathBuf> = [
+            ("a", "b/c"),
+            ("b", "d"),
+            ("up", ".."),
+            ("loop", "loop")
+        ].iter().map(|(path, target)| (PathBuf::from(path), PathBuf::from(target))).collect();
+        let resolve = |directory: &str, target: &str| resolve_symlink(Path::new(directory), Path::new(target), &symlink_targets, output_folder, 0);
+
+        assert_eq!(resolve("", "a"), Some(PathBuf::from("d/c")));
+        assert_eq!(resolve("x", "../a/e"), Some(PathBuf::from("d/c/e")));
+        assert_eq!(resolve("", "up"), None);
+        assert_eq!(resolve("x/y", "../../up/z"), None);
+        assert_eq!(resolve("", "loop"), None);
+        assert_eq!(resolve("", "/etc"), None);
+    }
+
+    #[test]
+    fn entry_paths_are_checked_without_touching_the_disk() {
+        let output_folder = Path::new("/nonexistent/arch-crypt-test");
+        let symlink_targets: HashMap<PathBuf, PathBuf> = [(PathBuf::from("link"), PathBuf::from("."))].into_iter().collect();
+        let check = |path: &str, unpack_options: UnpackOptions| check_entry_path(Path::new(path), &symlink_targets, output_folder, unpack_options);
+
+        assert_eq!(check("./a/b", HARDENED), Ok(Some(PathBuf::from("a/b"))));
+        assert!(check("/a", HARDENED).is_err());
+        assert_eq!(check("/a", UnpackOptions::default()), Ok(Some(PathBuf::from("a"))));
+        assert!(check("a/../b", UnpackOptions::default()).is_err());
+        assert!(check("link/b", HARDENED).is_err());
+        assert_eq!(check("a/b", UnpackOptions { strip_components: 1, ..HARDENED }), Ok(Some(PathBuf::from("b"))));
+        assert_eq!(check("a/b", UnpackOptions { strip_components: 2, ..HARDENED }), Ok(None));
+        assert!(check_hard_link(Path::new("/etc/passwd"), &symlink_targets, output_folder, HARDENED).is_err());
+        assert!(check_hard_link(Path::new("../x"), &symlink_targets, output_folder, HARDENED).is_err());
+    }
+}
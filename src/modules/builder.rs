@@ -0,0 +1,381 @@
+/*
+Copyright (C) 2023 Elijah Fry
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see https://www.gnu.org/licenses/gpl-3.0.html.
+*/
+
+
+use std::{io::{Read, Write}, path::{Path, PathBuf}};
+use zeroize::Zeroize;
+use crate::modules::{archiver::{open, seal, PackOptions, UnpackOptions}, encryption::{decrypt_file, encrypt_file, Credentials, DecryptingReader, EncryptingWriter, EncryptionOptions}, error::{Error, Result}, header::{Cipher, Compression, KdfParams}, key_file::read_key_file, recovery_key::{parse_recovery_key, RECOVERY_KEY_SIZE}, shamir::{read_share_file, Share}, x25519::{parse_public_key, read_private_key}};
+
+
+/// Encrypts data with a password, a key file, or both, and/or for the owners of X25519 public keys
+/// 
+/// # Notes
+/// Uses the default cipher (ChaCha20Poly1305), the default Argon2id parameters and no compression, unless you ask for something else.
+/// The password and key file are overwritten with 0s when the `Encryptor` is dropped
+#[derive(Default)]
+pub struct Encryptor {
+    credentials: Credentials,
+    key_file: Option<PathBuf>,
+    recipients: Vec<String>,
+    options: EncryptionOptions,
+    pack_options: PackOptions
+}
+
+impl Encryptor {
+    /// Returns an instance of `Encryptor` with the default options, and no password or key file
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the password that you want to use (It will be hashed using the Argon2id algorithm)
+    pub fn password<T: Into<String>>(mut self, password: T) -> Self {
+        if let Some(mut old_password) = self.credentials.password.replace(password.into()) {
+            old_password.zeroize();
+        }
+        self
+    }
+
+    /// Sets the key file that you want to use, instead of or as well as a password. It's read when the data is encrypted
+    pub fn key_file<T: AsRef<Path>>(mut self, key_file: T) -> Self {
+        self.key_file = Some(key_file.as_ref().to_path_buf());
+        self
+    }
+
+    /// Adds an X25519 public key (I.E. `x25519:BASE64`) that can decrypt the data, with the matching private key. It's parsed when the data is encrypted
+    /// 
+    /// # Notes
+    /// This can be called more than once, and can be combined with a password and/or key file. Any one of them can decrypt the data
+    pub fn recipient<T: Into<String>>(mut self, public_key: T) -> Self {
+        self.recipients.push(public_key.into());
+        self
+    }
+
+    /// Sets a recovery key (see `recovery_key::generate_recovery_key`) that can decrypt the data on its own, without the password
+    /// 
+    /// # Notes
+    /// The recovery key is only stored in encrypted form, so show it to the user (with `recovery_key::format_recovery_key`) before dropping it
+    pub fn recovery_key(mut self, recovery_key: [u8; RECOVERY_KEY_SIZE]) -> Self {
+        if let Some(mut old_recovery_key) = self.credentials.recovery_key.replace(recovery_key) {
+            old_recovery_key.zeroize();
+        }
+        self
+    }
+
+    /// Sets the Shamir shares (see `shamir::generate_shares`) that can decrypt the data, any threshold set of them together
+    /// 
+    /// # Notes
+    /// Every share of the split must be given. The key they were split from is never stored, so write them out (with `shamir::write_share_files`) before dropping them
+    pub fn shares(mut self, shares: Vec<Share>) -> Self {
+        self.credentials.shares = shares;
+        self
+    }
+
+    /// Sets the password, key material and public keys that you want to use, replacing any password, key file or recipients set before
+    pub fn credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = credentials;
+        self.key_file = None;
+        self.recipients.clear();
+        self
+    }
+
+    /// Sets the AEAD cipher used to encrypt the blocks
+    pub fn cipher(mut self, cipher: Cipher) -> Self {
+        self.options.cipher = cipher;
+        self
+    }
+
+    /// Sets the algorithm used to compress the data before it's encrypted
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.options.compression = compression;
+        self
+    }
+
+    /// Sets the Argon2id cost parameters used to hash the password
+    pub fn kdf_params(mut self, kdf_params: KdfParams) -> Self {
+        self.options.kdf_params = kdf_params;
+        self
+    }
+
+    /// Sets the number of key slots in the header (8 by default). Empty key slots can be filled later (see `key_slots::add_key_slots`)
+    pub fn key_slots(mut self, key_slots: u8) -> Self {
+        self.options.key_slots = key_slots;
+        self
+    }
+
+    /// Sets every option at once
+    pub fn options(mut self, options: EncryptionOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Sets how `seal` packs the directory, I.E. the root directory that its contents are stored under in the tarball
+    pub fn pack_options(mut self, pack_options: PackOptions) -> Self {
+        self.pack_options = pack_options;
+        self
+    }
+
+    /// Encrypts everything read from `reader`, and writes the encrypted file (header and blocks) to `writer`
+    /// 
+    /// # Returns
+    /// Returns the writer, after it was flushed
+    pub fn encrypt<R: Read, W: Write>(self, mut reader: R, writer: W) -> Result<W> {
+        let mut encrypting_writer = match self.writer(writer) {
+            Ok(resp) => resp,
+            Err(error) => {
+                return Err(error);
+            }
+        };
+
+        // Encrypt the data, then encrypt the final block
+        match std::io::copy(&mut reader, &mut encrypting_writer) {
+            Ok(_resp) => {},
+            Err(error) => {
+                return Err(Error::io("Failed to encrypt the data", error));
+            }
+        };
+        encrypting_writer.finish()
+    }
+
+    /// Returns an `EncryptingWriter` that encrypts everything written to it, after writing the header to `writer`
+    /// 
+    /// # Notes
+    /// `finish` must be called on the returned writer after the last write, otherwise the encrypted data will be truncated
+    pub fn writer<W: Write>(mut self, writer: W) -> Result<EncryptingWriter<W>> {
+        match self.take_credentials() {
+            Ok(credentials) => EncryptingWriter::new(writer, credentials, self.options),
+            Err(error) => Err(error)
+        }
+    }
+
+    /// Encrypts a file. The output file is never overwritten, and it's deleted if encryption fails
+    pub fn encrypt_file<I: AsRef<Path>, O: AsRef<Path>>(mut self, input_file: I, output_file: O) -> Result<()> {
+        match self.take_credentials() {
+            Ok(credentials) => encrypt_file(input_file.as_ref(), output_file.as_ref(), credentials, self.options),
+            Err(error) => Err(error)
+        }
+    }
+
+    /// Packs a directory into a tarball and encrypts it in one pass. See `archiver::seal`
+    pub fn seal<I: AsRef<Path>, O: AsRef<Path>>(mut self, input_directory: I, output_file: O) -> Result<()> {
+        match self.take_credentials() {
+            Ok(credentials) => seal(input_directory.as_ref(), output_file.as_ref(), credentials, self.options, self.pack_options),
+            Err(error) => Err(error)
+        }
+    }
+
+    fn take_credentials(&mut self) -> Result<Credentials> {
+        // Parse the public keys of the recipients
+        for public_key in &self.recipients {
+            match parse_public_key(public_key) {
+                Ok(resp) => self.credentials.recipients.push(resp),
+                Err(error) => {
+                    return Err(error);
+                }
+            };
+        }
+        self.recipients.clear();
+
+        take_credentials(&mut self.credentials, &self.key_file)
+    }
+}
+
+/// Decrypts data that was encrypted with a password, a key file, or both, or for an X25519 public key
+/// 
+/// # Notes
+/// The cipher, compression algorithm and Argon2id parameters are read from the header, so only the password and/or key file (or the private key) are needed.
+/// The password, key file, private key and recovery key are overwritten with 0s when the `Decryptor` is dropped
+#[derive(Default)]
+pub struct Decryptor {
+    credentials: Credentials,
+    key_file: Option<PathBuf>,
+    private_key_file: Option<PathBuf>,
+    recovery_key: Option<String>,
+    share_files: Vec<PathBuf>,
+    unpack_options: UnpackOptions
+}
+
+impl Decryptor {
+    /// Returns an instance of `Decryptor` with no password or key file
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the password you used to encrypt the data
+    pub fn password<T: Into<String>>(mut self, password: T) -> Self {
+        if let Some(mut old_password) = self.credentials.password.replace(password.into()) {
+            old_password.zeroize();
+        }
+        self
+    }
+
+    /// Sets the key file you used to encrypt the data. It's read when the data is decrypted
+    pub fn key_file<T: AsRef<Path>>(mut self, key_file: T) -> Self {
+        self.key_file = Some(key_file.as_ref().to_path_buf());
+        self
+    }
+
+    /// Sets the X25519 private key file (created by `x25519::generate_key_pair`) whose public key the data was encrypted for. It's read when the data is decrypted
+    pub fn private_key_file<T: AsRef<Path>>(mut self, private_key_file: T) -> Self {
+        self.private_key_file = Some(private_key_file.as_ref().to_path_buf());
+        self
+    }
+
+    /// Adds a share file (created by `shamir::write_share_files`). It's read when the data is decrypted
+    /// 
+    /// # Notes
+    /// This can be called more than once, and the data can be decrypted once the threshold number of share files is reached
+    pub fn share_file<T: AsRef<Path>>(mut self, share_file: T) -> Self {
+        self.share_files.push(share_file.as_ref().to_path_buf());
+        self
+    }
+
+    /// Sets the recovery key that was shown when the data was encrypted (I.E. `7KQ2-...-X4M9`). It's parsed when the data is decrypted
+    pub fn recovery_key<T: Into<String>>(mut self, recovery_key: T) -> Self {
+        if let Some(mut old_recovery_key) = self.recovery_key.replace(recovery_key.into()) {
+            old_recovery_key.zeroize();
+        }
+        self
+    }
+
+    /// Sets the password, key material, private key, recovery key and shares you used to encrypt the data, replacing any password, key file, private key file, recovery key or share files set before
+    pub fn credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = credentials;
+        self.key_file = None;
+        self.private_key_file = None;
+        self.share_files.clear();
+        if let Some(mut old_recovery_key) = self.recovery_key.take() {
+            old_recovery_key.zeroize();
+        }
+        self
+    }
+
+    /// Sets how `open` unpacks the sealed tarball, I.E. `hardened` for tarballs you don't trust, or `strip_components`
+    pub fn unpack_options(mut self, unpack_options: UnpackOptions) -> Self {
+        self.unpack_options = unpack_options;
+        self
+    }
+
+    /// Decrypts the encrypted file read from `reader`, and writes the plaintext to `writer`
+    /// 
+    /// # Notes
+    /// Every block is authenticated before it's written, but if decryption fails part way through, `writer` may contain some of the plaintext
+    /// 
+    /// # Returns
+    /// Returns the writer, after it was flushed
+    pub fn decrypt<R: Read, W: Write>(self, reader: R, mut writer: W) -> Result<W> {
+        // The password (or private key) is checked here, before anything is written
+        let mut decrypting_reader = match self.reader(reader) {
+            Ok(resp) => resp,
+            Err(error) => {
+                return Err(error);
+            }
+        };
+
+        match std::io::copy(&mut decrypting_reader, &mut writer) {
+            Ok(_resp) => {},
+            Err(error) => {
+                return Err(Error::io("Failed to decrypt the data", error));
+            }
+        };
+        match writer.flush() {
+            Ok(_resp) => Ok(writer),
+            Err(error) => Err(Error::io("Failed to flush the output", error))
+        }
+    }
+
+    /// Returns a `DecryptingReader` that decrypts the encrypted data read from `reader`, after reading the header and checking the password (or private key)
+    pub fn reader<R: Read>(mut self, reader: R) -> Result<DecryptingReader<R>> {
+        match self.take_credentials() {
+            Ok(credentials) => DecryptingReader::new(reader, credentials),
+            Err(error) => Err(error)
+        }
+    }
+
+    /// Decrypts a file. The password is checked before the output file is created, and the output file is deleted if decryption fails
+    pub fn decrypt_file<I: AsRef<Path>, O: AsRef<Path>>(mut self, input_file: I, output_file: O) -> Result<()> {
+        match self.take_credentials() {
+            Ok(credentials) => decrypt_file(input_file.as_ref(), output_file.as_ref(), credentials),
+            Err(error) => Err(error)
+        }
+    }
+
+    /// Decrypts a sealed tarball and unpacks it into a directory in one pass. See `archiver::open`
+    pub fn open<I: AsRef<Path>, O: AsRef<Path>>(mut self, input_file: I, output_directory: O) -> Result<()> {
+        match self.take_credentials() {
+            Ok(credentials) => open(input_file.as_ref(), output_directory.as_ref(), credentials, self.unpack_options),
+            Err(error) => Err(error)
+        }
+    }
+
+    fn take_credentials(&mut self) -> Result<Credentials> {
+        // Read the private key file
+        if let Some(private_key_file) = &self.private_key_file {
+            self.credentials.private_key = match read_private_key(private_key_file) {
+                Ok(resp) => Some(resp),
+                Err(error) => {
+                    return Err(error);
+                }
+            };
+        }
+
+        // Read the share files
+        for share_file in &self.share_files {
+            match read_share_file(share_file) {
+                Ok(resp) => self.credentials.shares.push(resp),
+                Err(error) => {
+                    return Err(error);
+                }
+            };
+        }
+        self.share_files.clear();
+
+        // Parse the recovery key
+        if let Some(mut recovery_key) = self.recovery_key.take() {
+            let parsed_recovery_key = parse_recovery_key(&recovery_key);
+            recovery_key.zeroize();
+            self.credentials.recovery_key = match parsed_recovery_key {
+                Ok(resp) => Some(resp),
+                Err(error) => {
+                    return Err(error);
+                }
+            };
+        }
+
+        take_credentials(&mut self.credentials, &self.key_file)
+    }
+}
+
+impl Drop for Decryptor {
+    fn drop(&mut self) {
+        if let Some(recovery_key) = self.recovery_key.as_mut() {
+            recovery_key.zeroize();
+        }
+    }
+}
+
+// Reads the key file (if there is one) into the credentials, and takes them out of the builder
+fn take_credentials(credentials: &mut Credentials, key_file: &Option<PathBuf>) -> Result<Credentials> {
+    if let Some(key_file) = key_file {
+        credentials.key_file = match read_key_file(key_file) {
+            Ok(resp) => Some(resp),
+            Err(error) => {
+                return Err(error);
+            }
+        };
+    }
+    Ok(std::mem::take(credentials))
+}
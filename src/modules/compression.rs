@@ -0,0 +1,202 @@
+/*
+Copyright (C) 2023 Elijah Fry
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see https://www.gnu.org/licenses/gpl-3.0.html.
+*/
+
+
+use std::io::{BufReader, Read, Result, Write};
+use crate::modules::header::Compression;
+
+
+// The zstd compression level. Level 3 is zstd's own default, and is a good balance between speed and size
+const ZSTD_LEVEL: i32 = 3;
+// The xz compression level. Level 6 is the default of the xz command-line tool
+const XZ_LEVEL: u32 = 6;
+
+// The magic bytes at the start of each compressed format, used to detect how a tarball was compressed
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+const SNAPPY_MAGIC: &[u8] = &[0xff, 0x06, 0x00, 0x00, b's', b'N', b'a', b'P', b'p', b'Y'];
+
+
+/// Works out which algorithm compressed some data, by looking at its magic bytes
+/// 
+/// # Arguments
+/// * `bytes` - The first few bytes of the data
+/// 
+/// # Notes
+/// Returns `Compression::None` if the data doesn't start with any magic bytes we know about
+pub(crate) fn detect_compression(bytes: &[u8]) -> Compression {
+    if bytes.starts_with(GZIP_MAGIC) {
+        Compression::Gzip
+    } else if bytes.starts_with(ZSTD_MAGIC) {
+        Compression::Zstd
+    } else if bytes.starts_with(XZ_MAGIC) {
+        Compression::Xz
+    } else if bytes.starts_with(SNAPPY_MAGIC) {
+        Compression::Snappy
+    } else {
+        Compression::None
+    }
+}
+
+/// Picks a compression algorithm from the extension of a tarball's file name (I.E. `.tar.gz`, `.tar.zst` or `.tar.xz`)
+pub fn compression_from_file_name(file_name: &str) -> Compression {
+    if file_name.ends_with(".tar.gz") || file_name.ends_with(".tgz") {
+        Compression::Gzip
+    } else if file_name.ends_with(".tar.zst") || file_name.ends_with(".tzst") {
+        Compression::Zstd
+    } else if file_name.ends_with(".tar.xz") || file_name.ends_with(".txz") {
+        Compression::Xz
+    } else {
+        Compression::None
+    }
+}
+
+
+/// Compresses everything written to it with the chosen algorithm, and writes the compressed bytes to the inner writer
+pub(crate) enum CompressingWriter<W: Write> {
+    None(W),
+    Zstd(zstd::Encoder<'static, W>),
+    Snappy(Box<snap::write::FrameEncoder<W>>),
+    Gzip(flate2::write::GzEncoder<W>),
+    Xz(xz2::write::XzEncoder<W>)
+}
+
+impl<W: Write> CompressingWriter<W> {
+    /// Returns an instance of `CompressingWriter` that compresses with the given algorithm
+    pub fn new(inner: W, compression: Compression) -> Result<Self> {
+        match compression {
+            Compression::None => Ok(Self::None(inner)),
+            Compression::Zstd => Ok(Self::Zstd(zstd::Encoder::new(inner, ZSTD_LEVEL)?)),
+            Compression::Snappy => Ok(Self::Snappy(Box::new(snap::write::FrameEncoder::new(inner)))),
+            Compression::Gzip => Ok(Self::Gzip(flate2::write::GzEncoder::new(inner, flate2::Compression::default()))),
+            Compression::Xz => Ok(Self::Xz(xz2::write::XzEncoder::new(inner, XZ_LEVEL)))
+        }
+    }
+
+    /// Writes the end of the compressed stream, and returns the inner writer
+    pub fn finish(self) -> Result<W> {
+        match self {
+            Self::None(inner) => Ok(inner),
+            Self::Zstd(encoder) => encoder.finish(),
+            Self::Snappy(encoder) => encoder.into_inner().map_err(|error| error.into_error()),
+            Self::Gzip(encoder) => encoder.finish(),
+            Self::Xz(encoder) => encoder.finish()
+        }
+    }
+
+    /// Returns a reference to the inner writer
+    pub fn get_ref(&self) -> &W {
+        match self {
+            Self::None(inner) => inner,
+            Self::Zstd(encoder) => encoder.get_ref(),
+            Self::Snappy(encoder) => encoder.get_ref(),
+            Self::Gzip(encoder) => encoder.get_ref(),
+            Self::Xz(encoder) => encoder.get_ref()
+        }
+    }
+
+    /// Returns a mutable reference to the inner writer
+    pub fn get_mut(&mut self) -> &mut W {
+        match self {
+            Self::None(inner) => inner,
+            Self::Zstd(encoder) => encoder.get_mut(),
+            Self::Snappy(encoder) => encoder.get_mut(),
+            Self::Gzip(encoder) => encoder.get_mut(),
+            Self::Xz(encoder) => encoder.get_mut()
+        }
+    }
+}
+
+impl<W: Write> Write for CompressingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match self {
+            Self::None(inner) => inner.write(buf),
+            Self::Zstd(encoder) => encoder.write(buf),
+            Self::Snappy(encoder) => encoder.write(buf),
+            Self::Gzip(encoder) => encoder.write(buf),
+            Self::Xz(encoder) => encoder.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            Self::None(inner) => inner.flush(),
+            Self::Zstd(encoder) => encoder.flush(),
+            Self::Snappy(encoder) => encoder.flush(),
+            Self::Gzip(encoder) => encoder.flush(),
+            Self::Xz(encoder) => encoder.flush()
+        }
+    }
+}
+
+/// Decompresses everything read from the inner reader with the chosen algorithm
+pub(crate) enum DecompressingReader<R: Read> {
+    None(R),
+    Zstd(zstd::Decoder<'static, BufReader<R>>),
+    Snappy(snap::read::FrameDecoder<R>),
+    Gzip(flate2::read::MultiGzDecoder<R>),
+    Xz(xz2::read::XzDecoder<R>)
+}
+
+impl<R: Read> DecompressingReader<R> {
+    /// Returns an instance of `DecompressingReader` that decompresses with the given algorithm
+    pub fn new(inner: R, compression: Compression) -> Result<Self> {
+        match compression {
+            Compression::None => Ok(Self::None(inner)),
+            Compression::Zstd => Ok(Self::Zstd(zstd::Decoder::new(inner)?)),
+            Compression::Snappy => Ok(Self::Snappy(snap::read::FrameDecoder::new(inner))),
+            // Note: The gzip and xz formats allow several compressed streams to be joined together, so we use the decoders that read all of them
+            Compression::Gzip => Ok(Self::Gzip(flate2::read::MultiGzDecoder::new(inner))),
+            Compression::Xz => Ok(Self::Xz(xz2::read::XzDecoder::new_multi_decoder(inner)))
+        }
+    }
+
+    /// Returns a reference to the inner reader
+    pub fn get_ref(&self) -> &R {
+        match self {
+            Self::None(inner) => inner,
+            Self::Zstd(decoder) => decoder.get_ref().get_ref(),
+            Self::Snappy(decoder) => decoder.get_ref(),
+            Self::Gzip(decoder) => decoder.get_ref(),
+            Self::Xz(decoder) => decoder.get_ref()
+        }
+    }
+
+    /// Returns a mutable reference to the inner reader
+    pub fn get_mut(&mut self) -> &mut R {
+        match self {
+            Self::None(inner) => inner,
+            Self::Zstd(decoder) => decoder.get_mut().get_mut(),
+            Self::Snappy(decoder) => decoder.get_mut(),
+            Self::Gzip(decoder) => decoder.get_mut(),
+            Self::Xz(decoder) => decoder.get_mut()
+        }
+    }
+}
+
+impl<R: Read> Read for DecompressingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match self {
+            Self::None(inner) => inner.read(buf),
+            Self::Zstd(decoder) => decoder.read(buf),
+            Self::Snappy(decoder) => decoder.read(buf),
+            Self::Gzip(decoder) => decoder.read(buf),
+            Self::Xz(decoder) => decoder.read(buf)
+        }
+    }
+}
@@ -16,36 +16,149 @@ along with this program.  If not, see https://www.gnu.org/licenses/gpl-3.0.html.
 */
 
 
-use argon2::{Argon2, password_hash::rand_core::RngCore};
+use argon2::{Algorithm, Argon2, Params, Version, password_hash::rand_core::RngCore};
+use blake2::{Blake2bMac, digest::{consts::U32, Mac}};
 use chacha20::ChaCha20;
+use aes_gcm_siv::Aes256GcmSiv;
 use chacha20poly1305::{
-    aead::{Aead, KeyInit, OsRng},
+    aead::{Aead, KeyInit, OsRng, Payload, generic_array::GenericArray, stream::{NewStream, StreamBE32, StreamPrimitive}},
     ChaCha20Poly1305,
     ChaChaPoly1305,
+    XChaCha20Poly1305,
     consts::U12
 };
-use log::error;
+use std::{io::{BufReader, BufWriter, ErrorKind, Read, Write}, path::Path};
+use log::warn;
 use zeroize::Zeroize;
-use crate::modules::file_handler::FileReaderWriter;
+use crate::modules::{archiver::delete_file, error::{Error, Result}, compression::{CompressingWriter, DecompressingReader}, file_handler::{create_output_file, open_input_file}, key_slots::explain_modified_header, header::{Cipher, Compression, Header, KdfParams, Recipient, DEFAULT_KEY_SLOTS, HEADER_CORE_SIZE, HEADER_MAC_SIZE, HEADER_PREFIX_SIZE, MAX_MEMORY_COST, MAX_PARALLELISM, MAX_TIME_COST, WRAPPED_KEY_SIZE}, key_file::KEY_FILE_SIZE, recovery_key::{unwrap_with_recovery_key, wrap_for_recovery_key, RECOVERY_KEY_SIZE}, shamir::{matching_shares, unwrap_with_shares, wrap_for_shares, Share}, x25519::{unwrap_with_private_key, wrap_for_public_key}};
 
 
 // The BLOCK_SIZE is the size of bytes we encrypt at a time
 // Note that when encrypting a block of bytes, a 16byte 'checksum' of sorts will be appended to the end (BLOCK_SIZE + 16)
 const BLOCK_SIZE: usize = 32768;
 
+// The largest random nonce prefix used by the STREAM construction (XChaCha20-Poly1305 has a 24-byte nonce)
+// The remaining 5 bytes of the nonce hold a 32-bit block counter and a 'last block' flag
+pub(crate) const MAX_NONCE_PREFIX_SIZE: usize = 19;
+
+// When a block fails to decrypt, we check if it belongs at any of the positions up to this many blocks before or after it
+const REORDER_SEARCH_WINDOW: u64 = 256;
+
+// The number of blocks in every segment, for ciphers that use a new key for every segment (32 GiB with the default block size)
+#[cfg(not(test))]
+const SEGMENT_SIZE: u64 = 1 << 20;
+
+// The tests use tiny segments, so they can cross a segment boundary without encrypting 32 GiB
+#[cfg(test)]
+const SEGMENT_SIZE: u64 = 4;
+
+// The message hashed with the encryption key (and the segment index) to derive the key of every segment
+const SEGMENT_KEY_MESSAGE: &[u8] = b"arch-crypt segment key";
+
+// The message hashed with the file key to derive the key that encrypts the blocks
+const PAYLOAD_KEY_MESSAGE: &[u8] = b"arch-crypt payload key";
+
+// The message hashed with the file key (and the rest of the header) to create the header MAC
+const HEADER_MAC_MESSAGE: &[u8] = b"arch-crypt header mac";
+
+// The message hashed with the key file (and the salt) to derive the key that encrypts the file key, when there's no password
+const KEY_FILE_KEY_MESSAGE: &[u8] = b"arch-crypt key file key";
+
+// The message hashed with the password hash (and the key file) to derive the key that encrypts the file key, when there's a password and a key file
+const PASSWORD_AND_KEY_FILE_KEY_MESSAGE: &[u8] = b"arch-crypt password and key file key";
+type Blake2bMac256 = Blake2bMac<U32>;
+
+
+/// Options that control how data is encrypted
+#[derive(Clone, Copy, Debug)]
+pub struct EncryptionOptions {
+    /// The AEAD cipher used to encrypt the blocks
+    pub cipher: Cipher,
+    /// The Argon2id cost parameters used to hash the password
+    pub kdf_params: KdfParams,
+    /// The algorithm used to compress the data before it's encrypted
+    pub compression: Compression,
+    /// The number of key slots in the header. The ones that aren't used by the credentials are left empty, so more can be added later (see `key_slots`)
+    pub key_slots: u8
+}
+
+impl Default for EncryptionOptions {
+    fn default() -> Self {
+        Self {
+            cipher: Cipher::default(),
+            kdf_params: KdfParams::default(),
+            compression: Compression::default(),
+            key_slots: DEFAULT_KEY_SLOTS
+        }
+    }
+}
+
+/// The secrets that can decrypt a file: a password, a key file, or both, and/or the public keys of the recipients, a recovery key and Shamir shares
+/// 
+/// # Notes
+/// If a password and a key file are both used, the encrypted file can only be decrypted by someone who knows the password and has the key file.
+/// Every public key in `recipients` can decrypt the file on its own, using the matching private key, and so can the recovery key and any threshold set of the shares.
+/// The password, key file, private key, recovery key and shares are overwritten with 0s when the `Credentials` are dropped
+#[derive(Default)]
+pub struct Credentials {
+    /// The password (It will be hashed using the Argon2id algorithm)
+    pub password: Option<String>,
+    /// The key material read from a key file (see `key_file::read_key_file`)
+    pub key_file: Option<[u8; KEY_FILE_SIZE]>,
+    /// The X25519 public keys that the file is encrypted for (see `x25519::parse_public_key`). Only used when encrypting
+    pub recipients: Vec<[u8; 32]>,
+    /// The X25519 private key read from a private key file (see `x25519::read_private_key`). Only used when decrypting
+    pub private_key: Option<[u8; 32]>,
+    /// A recovery key (see `recovery_key::generate_recovery_key`). When encrypting, it gets its own key slot, so it can decrypt the file without the password
+    pub recovery_key: Option<[u8; RECOVERY_KEY_SIZE]>,
+    /// Shamir shares of a split key (see `shamir::generate_shares`). When encrypting, every share must be given, and when decrypting, at least the threshold
+    pub shares: Vec<Share>
+}
+
+impl From<String> for Credentials {
+    fn from(password: String) -> Self {
+        Self {
+            password: Some(password),
+            key_file: None,
+            recipients: Vec::new(),
+            private_key: None,
+            recovery_key: None,
+            shares: Vec::new()
+        }
+    }
+}
+
+impl Drop for Credentials {
+    fn drop(&mut self) {
+        if let Some(password) = self.password.as_mut() {
+            password.zeroize();
+        }
+        if let Some(key_file) = self.key_file.as_mut() {
+            key_file.zeroize();
+        }
+        if let Some(private_key) = self.private_key.as_mut() {
+            private_key.zeroize();
+        }
+        if let Some(recovery_key) = self.recovery_key.as_mut() {
+            recovery_key.zeroize();
+        }
+    }
+}
 
 /// Hashes a password using the Argon2id algorithm
 /// 
 /// # Arguments
 /// * `plaintext_password` - The password that you want hashed
 /// * `input_salt` - Optional, Allows you to specify a salt to use when hashing the password
+/// * `kdf_params` - The Argon2id cost parameters to use
 /// 
 /// # Notes
-/// Choosing an input_salt is useful when trying to recreate an encryption key used to encrypt a file
+/// Choosing an input_salt is useful when trying to recreate an encryption key used to encrypt a file.
+/// The same goes for kdf_params, which must match the parameters stored in the header of the file
 /// 
 /// # Returns
 /// A tuple containing the hashed password and the salt used.
-fn hash_password(mut plaintext_password: String, input_salt: Option<[u8; 32]>) -> Result<([u8; 32], [u8; 32]), ()> {
+fn hash_password(plaintext_password: &str, input_salt: Option<[u8; 32]>, kdf_params: KdfParams) -> Result<([u8; 32], [u8; 32])> {
     // Variable declarations
     let mut password_hash = [0u8; 32];
     let mut salt = [0u8; 32];
@@ -60,283 +173,1695 @@ fn hash_password(mut plaintext_password: String, input_salt: Option<[u8; 32]>) -
         }
     }
 
+    // Build the Argon2id hasher from the cost parameters
+    // Note: Files with parameters above the limits couldn't be decrypted, so they aren't created either
+    if !kdf_params.is_within_limits() {
+        return Err(Error::Crypto(format!("Invalid Argon2id parameters {kdf_params:?}: the limits are {MAX_MEMORY_COST} KiB of memory, {MAX_TIME_COST} iterations and {MAX_PARALLELISM} threads")));
+    }
+    let params = match Params::new(kdf_params.memory_cost, kdf_params.time_cost, kdf_params.parallelism, Some(password_hash.len())) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(Error::Crypto(format!("Invalid Argon2id parameters {kdf_params:?}: {error}")));
+        }
+    };
+    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+
     // Compute 256-bit hash from plaintext password
-    match Argon2::default().hash_password_into(plaintext_password.as_bytes(), &salt, &mut password_hash) {
+    match argon2.hash_password_into(plaintext_password.as_bytes(), &salt, &mut password_hash) {
         Ok(_) => {},
         Err(error) => {
-            error!("Couldn't hash the password: \n{error}");
-            return Err(());
+            return Err(Error::Crypto(format!("Couldn't hash the password: {error}")));
         }
     };
 
-    // Zeroize the plaintext_password for security
-    plaintext_password.zeroize();
-
     // Return the hash and the salt we used
     let encryption_key = (password_hash, salt);
     
     Ok(encryption_key)
 }
 
-/// Encrypts a a file using a plaintext password
+/// Derives the key that encrypts the file key from a password and/or a key file
 /// 
 /// # Arguments
-/// * `input_file` - The location of the file you want to encrypt
-/// * `output_file` - The location of the output file
-/// * `plaintext_password` - The password that you want use (It will be hashed using the Argon2id algorithm)
-pub fn encrypt_file(input_file: String, output_file: String, plaintext_password: String) -> Result<(), ()> {
-    // Try to initialize I/O file writer
-    let mut file_rw;
-    match FileReaderWriter::new(&input_file, &output_file) {
-        Ok(resp) => file_rw = resp,
-        Err(_error) => {
-            return Err(());
+/// * `password` - The password, if the recipient uses one
+/// * `key_file` - The key material read from the key file, if the recipient uses one
+/// * `salt` - The salt stored with the recipient
+/// * `kdf_params` - The Argon2id cost parameters stored with the recipient
+fn derive_wrap_key(password: Option<&str>, key_file: Option<&[u8; KEY_FILE_SIZE]>, salt: [u8; 32], kdf_params: KdfParams) -> Result<[u8; 32]> {
+    match (password, key_file) {
+        // Hash the password into a 256bit key
+        (Some(password), None) => {
+            match hash_password(password, Some(salt), kdf_params) {
+                Ok((resp, _salt)) => Ok(resp),
+                Err(error) => Err(error)
+            }
+        },
+        // Hash the key file with the salt, so every file gets a different key
+        (None, Some(key_file)) => keyed_hash(key_file, KEY_FILE_KEY_MESSAGE, &salt),
+        // Hash the password, then combine it with the key file, so both are needed
+        (Some(password), Some(key_file)) => {
+            let mut password_hash = match hash_password(password, Some(salt), kdf_params) {
+                Ok((resp, _salt)) => resp,
+                Err(error) => {
+                    return Err(error);
+                }
+            };
+            let key = keyed_hash(&password_hash, PASSWORD_AND_KEY_FILE_KEY_MESSAGE, key_file);
+            password_hash.zeroize();
+            key
+        },
+        (None, None) => Err(Error::Crypto("No password or key file was given".to_string()))
+    }
+}
+
+/// Encrypts the file key with a key derived from a password and/or a key file
+/// 
+/// # Arguments
+/// * `file_key` - The random key of the file
+/// * `password` - The password, if there is one
+/// * `key_file` - The key material read from the key file, if there is one
+/// * `kdf_params` - The Argon2id cost parameters to use
+pub(crate) fn wrap_for_credentials(file_key: &[u8; 32], password: Option<&str>, key_file: Option<&[u8; KEY_FILE_SIZE]>, kdf_params: KdfParams) -> Result<Recipient> {
+    // Derive the key that encrypts the file key, using a random salt
+    let mut salt = [0u8; 32];
+    OsRng.fill_bytes(&mut salt);
+    let mut wrap_key = match derive_wrap_key(password, key_file, salt, kdf_params) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(error);
         }
     };
-
-    // Hash plaintext_password into a 256bit key
-    let (mut encryption_key, salt) = match hash_password(plaintext_password, None) {
-        Ok(resp) => {
-            resp
-        },
-        Err(_error) => {
-            return Err(());
+    let wrapped_key = wrap_file_key(&wrap_key, file_key);
+    wrap_key.zeroize();
+    let wrapped_key = match wrapped_key {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(error);
         }
     };
 
-    // Initialize cryptor
-    let cryptor = Cryptor::new(encryption_key, None);
+    match (password.is_some(), key_file.is_some()) {
+        (true, false) => Ok(Recipient::Password { kdf_params, salt, wrapped_key }),
+        (false, true) => Ok(Recipient::KeyFile { salt, wrapped_key }),
+        _ => Ok(Recipient::PasswordAndKeyFile { kdf_params, salt, wrapped_key })
+    }
+}
 
-    // Zeroize the encryption_key for security
-    encryption_key.zeroize();
+/// Tries to decrypt the file key of a recipient with the credentials
+/// 
+/// # Returns
+/// The file key, or `None` if the credentials are wrong (or this recipient needs credentials that we don't have)
+pub(crate) fn unwrap_for_credentials(recipient: &Recipient, credentials: &Credentials) -> Result<Option<[u8; 32]>> {
+    let password = credentials.password.as_deref();
+    let key_file = credentials.key_file.as_ref();
 
-    // Write the salt and nonce that we used to the start of the output file
-    match file_rw.write(&salt) {
-        Ok(_resp) => {},
-        Err(_error) => {
-            return Err(());
+    // Work out the key that encrypts the file key, if we have the credentials it needs
+    let (wrap_key, wrapped_key) = match recipient {
+        Recipient::Password { kdf_params, salt, wrapped_key } if password.is_some() => (derive_wrap_key(password, None, *salt, *kdf_params), wrapped_key),
+        Recipient::KeyFile { salt, wrapped_key } if key_file.is_some() => (derive_wrap_key(None, key_file, *salt, KdfParams::default()), wrapped_key),
+        Recipient::PasswordAndKeyFile { kdf_params, salt, wrapped_key } if password.is_some() && key_file.is_some() => (derive_wrap_key(password, key_file, *salt, *kdf_params), wrapped_key),
+        Recipient::X25519 { ephemeral_public_key, wrapped_key } => {
+            return match &credentials.private_key {
+                Some(private_key) => unwrap_with_private_key(private_key, ephemeral_public_key, wrapped_key),
+                None => Ok(None)
+            };
+        },
+        Recipient::RecoveryKey { salt, wrapped_key } => {
+            return match &credentials.recovery_key {
+                Some(recovery_key) => unwrap_with_recovery_key(recovery_key, salt, wrapped_key),
+                None => Ok(None)
+            };
+        },
+        Recipient::Shares { split_id, threshold, wrapped_key, .. } => {
+            return unwrap_with_shares(&credentials.shares, split_id, *threshold, wrapped_key);
+        },
+        _ => {
+            return Ok(None);
         }
     };
-    match file_rw.write(&cryptor.nonce) {
-        Ok(_resp) => {},
-        Err(_error) => {
-            return Err(());
+    let mut wrap_key = match wrap_key {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(error);
         }
     };
 
-    // Define a few variables
-    let total_file_size_bytes = file_rw.input_file_metadata.len() as usize;
-    let mut total_num_bytes_read: usize = 0;
-    let mut read_file_buffer = [0u8; BLOCK_SIZE];
+    let file_key = unwrap_file_key(&wrap_key, wrapped_key);
+    wrap_key.zeroize();
 
-    // Iterate through the input file in (BLOCK_SIZE) chunks and encrypt the bytes
-    while total_num_bytes_read < total_file_size_bytes {
-        // Try to read bytes from the input file
-        let num_bytes_read = match file_rw.read(&mut read_file_buffer) {
-            Ok(resp) => resp,
-            Err(_error) => {
-                return Err(());
-            }
-        };
-        total_num_bytes_read += num_bytes_read;
+    file_key
+}
 
-        // Encrypt the bytes and try to write them to the output file
-        let encrypted_bytes = match cryptor.encrypt_bytes(&read_file_buffer[..num_bytes_read]) {
-            Ok(resp) => {
-                resp
-            },
-            Err(_error) => {
-                return Err(());
-            },
-        };
-        
-        // Try to write encrypted bytes to the output file
-        match file_rw.write(encrypted_bytes.as_ref()) {
-            Ok(_resp) => {},
-            Err(_error) => {
-                return Err(());
-            }
-        };
+/// Returns whether a recipient could be decrypted with the credentials, if they're correct
+pub(crate) fn can_unwrap(recipient: &Recipient, credentials: &Credentials) -> bool {
+    match recipient {
+        Recipient::Password { .. } => credentials.password.is_some(),
+        Recipient::KeyFile { .. } => credentials.key_file.is_some(),
+        Recipient::PasswordAndKeyFile { .. } => credentials.password.is_some() && credentials.key_file.is_some(),
+        Recipient::X25519 { .. } => credentials.private_key.is_some(),
+        Recipient::RecoveryKey { .. } => credentials.recovery_key.is_some(),
+        Recipient::Shares { split_id, threshold, .. } => matching_shares(&credentials.shares, split_id).len() >= usize::from(*threshold)
     }
-
-    // Return an ok result
-    Ok(())
 }
 
-/// Decrypts a a file using a plaintext password
-/// 
-/// # Arguments
-/// * `input_file` - The location of the encrypted file
-/// * `output_file` - The location where you want the output file
-/// * `plaintext_password` - The password you used to encrypt the file
+/// Encrypts a file key with ChaCha20-Poly1305
 /// 
 /// # Notes
-/// The plaintext_password will be hashed using the Argon2id algorithm and the salt that was stored in the file during the initial encryption process
-pub fn decrypt_file(input_file: String, output_file: String, plaintext_password: String) -> Result<(), ()> {
-    // Try to initialize input and output file writer
-    let mut file_rw;
-    match FileReaderWriter::new(&input_file, &output_file) {
-        Ok(resp) => file_rw = resp,
-        Err(_error) => {
-            return Err(());
-        }
-    };
-
-    // Read salt and nonce from the start of the input file
-    let mut salt = [0u8; 32];
-    let mut nonce = [0u8; 12];
-    match file_rw.read(&mut salt) {
-        Ok(_resp) => {},
-        Err(_error) => {
-            return Err(());
-        }
-    };
-    match file_rw.read(&mut nonce) {
-        Ok(_resp) => {},
-        Err(_error) => {
-            return Err(());
-        }
-    };
-
-    // Hash plaintext_password into a 256bit key
-    let (mut encryption_key, _salt) = match hash_password(plaintext_password, Some(salt)) {
+/// Every key that encrypts a file key is only ever used once (it's derived from a random salt or a random ephemeral key), so the nonce is always 0
+pub(crate) fn wrap_file_key(wrap_key: &[u8; 32], file_key: &[u8; 32]) -> Result<[u8; WRAPPED_KEY_SIZE]> {
+    let cipher = ChaCha20Poly1305::new(GenericArray::from_slice(wrap_key));
+    let mut wrapped_key = [0u8; WRAPPED_KEY_SIZE];
+    match cipher.encrypt(&GenericArray::default(), file_key.as_slice()) {
         Ok(resp) => {
-            resp
+            wrapped_key.copy_from_slice(&resp);
+            Ok(wrapped_key)
         },
-        Err(_error) => {
-            return Err(());
-        }
-    };
-
-    // Initialize the cryptor
-    let cryptor = Cryptor::new(encryption_key, Some(nonce));
+        Err(_error) => Err(Error::Crypto("Failed to encrypt the file key".to_string()))
+    }
+}
 
-    // Zeroize the encryption_key for security
-    encryption_key.zeroize();
+/// Decrypts a file key encrypted by `wrap_file_key`
+/// 
+/// # Returns
+/// The file key, or `None` if `wrap_key` is wrong
+pub(crate) fn unwrap_file_key(wrap_key: &[u8; 32], wrapped_key: &[u8; WRAPPED_KEY_SIZE]) -> Result<Option<[u8; 32]>> {
+    let cipher = ChaCha20Poly1305::new(GenericArray::from_slice(wrap_key));
+    match cipher.decrypt(&GenericArray::default(), wrapped_key.as_slice()) {
+        Ok(mut resp) => {
+            let mut file_key = [0u8; 32];
+            file_key.copy_from_slice(&resp);
+            resp.zeroize();
+            Ok(Some(file_key))
+        },
+        Err(_error) => Ok(None)
+    }
+}
 
-    // Define a few variables
-    let total_file_size_bytes = file_rw.input_file_metadata.len() as usize;
-    let mut total_num_bytes_read: usize = 44;
-    let mut read_file_buffer = [0u8; BLOCK_SIZE + 16]; // We must read 16 extra bytes for the Poly1305 checksum
+/// Encrypts a file key for everyone in the credentials
+/// 
+/// # Arguments
+/// * `file_key` - The random key of the file
+/// * `credentials` - The password and/or key file, the public keys of the recipients, the recovery key and the shares
+/// * `kdf_params` - The Argon2id cost parameters used to hash the password
+/// 
+/// # Returns
+/// The recipients, in the order that they should be stored in the key slots of the header
+pub(crate) fn wrap_for_everyone(file_key: &[u8; 32], credentials: &Credentials, kdf_params: KdfParams) -> Result<Vec<Recipient>> {
+    // Make sure that someone will be able to decrypt the file
+    let num_recipients = credentials.recipients.len() + usize::from(credentials.password.is_some() || credentials.key_file.is_some()) + usize::from(credentials.recovery_key.is_some()) + usize::from(!credentials.shares.is_empty());
+    if num_recipients == 0 {
+        return Err(Error::Crypto("No password, key file or recipient was given".to_string()));
+    }
 
-    // Iterate through the input file in (BLOCK_SIZE) chunks and encrypt the bytes
-    while total_num_bytes_read < total_file_size_bytes {
-        // Try to read bytes from the input file
-        let num_bytes_read = match file_rw.read(&mut read_file_buffer) {
-            Ok(resp) => resp,
-            Err(_error) => {
-                return Err(());
+    // Encrypt the file key with the password and/or key file, then for every public key
+    let mut recipients = Vec::with_capacity(num_recipients);
+    if credentials.password.is_some() || credentials.key_file.is_some() {
+        match wrap_for_credentials(file_key, credentials.password.as_deref(), credentials.key_file.as_ref(), kdf_params) {
+            Ok(resp) => recipients.push(resp),
+            Err(error) => {
+                return Err(error);
             }
         };
-        total_num_bytes_read += num_bytes_read;
-
-        // Encrypt the bytes and try to write them to the output file
-        let decrypted_bytes = match cryptor.decrypt_bytes(&read_file_buffer[..num_bytes_read]) {
-            Ok(resp) => {
-                resp
-            },
-            Err(_error) => {
-                return Err(());
+    }
+    for public_key in &credentials.recipients {
+        match wrap_for_public_key(file_key, public_key) {
+            Ok(resp) => recipients.push(resp),
+            Err(error) => {
+                return Err(error);
             }
         };
-        match file_rw.write(decrypted_bytes.as_ref()) {
-            Ok(_resp) => {},
-            Err(_error) => {
-                return Err(());
+    }
+    if let Some(recovery_key) = &credentials.recovery_key {
+        match wrap_for_recovery_key(file_key, recovery_key) {
+            Ok(resp) => recipients.push(resp),
+            Err(error) => {
+                return Err(error);
+            }
+        };
+    }
+    if !credentials.shares.is_empty() {
+        match wrap_for_shares(file_key, &credentials.shares) {
+            Ok(resp) => recipients.push(resp),
+            Err(error) => {
+                return Err(error);
             }
         };
     }
 
-    Ok(())
+    Ok(recipients)
 }
 
-/// Used to encrypt/decrypt byte arrays with a given key and optionally nonces
-pub struct Cryptor {
-    cipher: ChaChaPoly1305<ChaCha20, U12>,
-    nonce: [u8; 12]
-}
+/// Generates a random file key, and encrypts it for everyone in the credentials
+/// 
+/// # Arguments
+/// * `credentials` - The password and/or key file, and the public keys of the recipients
+/// * `options` - The Argon2id cost parameters used to hash the password, and the number of key slots
+/// 
+/// # Returns
+/// A tuple containing the file key and the key slots that are stored in the header
+fn prepare_key_slots(credentials: &Credentials, options: &EncryptionOptions) -> Result<([u8; 32], Vec<Option<Recipient>>)> {
+    if credentials.private_key.is_some() {
+        warn!("A private key isn't needed to encrypt a file, so it's ignored");
+    }
 
-impl Cryptor {
-    /// Returns an instance `Cryptor` with the provided arguments
-    ///
-    /// # Arguments
-    /// * `key` - The 256-bit key used for encryption/decryption
-    /// * `input_nonce` - Optional, allows you to specicify a nonce (number-used-once)
-    /// 
-    /// # Notes
-    /// Specifying an input_nonce is useful when decrypting a file. You will need to use the same nonce that was used to encrypt the data
-    pub fn new(mut key: [u8; 32], input_nonce: Option<[u8; 12]>) -> Self {
-        // Create ChaCha20Poly1305 cipher using our 256bit key
-        let cipher = ChaCha20Poly1305::new(&key.into());
-        
-        // Zeroize the encryption key for security
-        key.zeroize();
+    // Generate the file key, which is what actually encrypts the file
+    let mut file_key = [0u8; 32];
+    OsRng.fill_bytes(&mut file_key);
 
-        // Use provided nonce or generate a random one
-        let mut nonce = [0u8; 12];
-        match input_nonce {
-            Some(resp) => {
-                nonce = resp;
-            },
-            None => {
-                OsRng.fill_bytes(&mut nonce);
-            },
-        };
-        
-        // Return Cryptor instance
-        Self {
-            cipher,
-            nonce
+    // Encrypt the file key for everyone, then leave the rest of the key slots empty
+    let mut slots: Vec<Option<Recipient>> = match wrap_for_everyone(&file_key, credentials, options.kdf_params) {
+        Ok(resp) => resp.into_iter().map(Some).collect(),
+        Err(error) => {
+            file_key.zeroize();
+            return Err(error);
         }
+    };
+    if slots.len() > u8::MAX as usize {
+        file_key.zeroize();
+        return Err(Error::Crypto(format!("A file can't be encrypted for more than {} recipients", u8::MAX)));
+    }
+    if slots.len() < options.key_slots as usize {
+        slots.resize(options.key_slots as usize, None);
     }
 
-    /// Encrypts a given slice of bytes
-    /// 
-    /// # Arguments
-    /// * `buffer` - The buffer of bytes that you want encrypted
-    /// 
-    /// # Returns
-    /// Returns a Vector of bytes (Vec\<u8>)
-    pub fn encrypt_bytes(&self, buffer: &[u8]) -> Result<Vec<u8>, ()> {
-        let encrypted_bytes = match self.cipher.encrypt(&self.nonce.into(), buffer) {
-            Ok(resp) => {
-                resp
-            },
-            Err(error) => {
-                error!("Failed to encrypt bytes:\n {error}");
-                return Err(());
+    Ok((file_key, slots))
+}
+
+/// Finds a key slot in the header that the credentials can decrypt, and returns the file key
+/// 
+/// # Returns
+/// A tuple containing the index of the key slot and the file key
+/// 
+/// # Notes
+/// This fails with an 'Incorrect password or key' error if none of the key slots that the credentials could decrypt match (or there are none), without decrypting any blocks
+pub(crate) fn find_file_key(header: &Header, credentials: &Credentials) -> Result<(usize, [u8; 32])> {
+    let recipients = || header.slots.iter().enumerate().filter_map(|(index, slot)| slot.as_ref().map(|recipient| (index, recipient)));
+
+    // Credentials that the file doesn't need are ignored
+    let uses_password = recipients().any(|(_index, recipient)| matches!(recipient, Recipient::Password { .. } | Recipient::PasswordAndKeyFile { .. }));
+    let uses_key_file = recipients().any(|(_index, recipient)| matches!(recipient, Recipient::KeyFile { .. } | Recipient::PasswordAndKeyFile { .. }));
+    let uses_private_key = recipients().any(|(_index, recipient)| matches!(recipient, Recipient::X25519 { .. }));
+    let uses_recovery_key = recipients().any(|(_index, recipient)| matches!(recipient, Recipient::RecoveryKey { .. }));
+    if credentials.password.is_some() && !uses_password {
+        warn!("The input file can't be decrypted with a password, so the password isn't needed");
+    }
+    if credentials.key_file.is_some() && !uses_key_file {
+        warn!("The input file can't be decrypted with a key file, so the key file isn't needed");
+    }
+    if credentials.private_key.is_some() && !uses_private_key {
+        warn!("The input file wasn't encrypted for any X25519 public keys, so the private key isn't needed");
+    }
+    if credentials.recovery_key.is_some() && !uses_recovery_key {
+        warn!("The input file doesn't have a recovery key, so the recovery key isn't needed");
+    }
+    if !credentials.shares.is_empty() {
+        let mut shares_match = false;
+        for (_index, recipient) in recipients() {
+            if let Recipient::Shares { split_id, threshold, .. } = recipient {
+                let num_matching = matching_shares(&credentials.shares, split_id).len();
+                shares_match |= num_matching > 0;
+                if num_matching > 0 && num_matching < usize::from(*threshold) {
+                    warn!("Only {num_matching} of the {threshold} shares needed to decrypt the input file were given");
+                }
             }
-        };
-        Ok(encrypted_bytes)
+        }
+        if !shares_match {
+            warn!("The shares weren't split for the input file, so they're ignored");
+        }
+    }
+    if !credentials.recipients.is_empty() {
+        warn!("Public keys aren't needed to decrypt a file, so they're ignored");
     }
 
-    /// Decrypts a given slice of bytes
-    /// 
-    /// # Arguments
-    /// * `buffer` - The buffer of bytes that you want decrypted
-    /// 
-    /// # Returns
-    /// Returns a Vector of bytes (Vec\<u8>)
-    pub fn decrypt_bytes(&self, buffer: &[u8]) -> Result<Vec<u8>, ()> {
-        let decrypted_bytes = match self.cipher.decrypt(&self.nonce.into(), buffer) {
-            Ok(resp) => {
-                resp
+    // Try every key slot that we have the credentials for, until one of them works
+    let mut tried = false;
+    for (index, recipient) in recipients().filter(|(_index, recipient)| can_unwrap(recipient, credentials)) {
+        tried = true;
+        match unwrap_for_credentials(recipient, credentials) {
+            Ok(Some(resp)) => {
+                return Ok((index, resp));
             },
+            Ok(None) => {},
             Err(error) => {
-                error!("Failed to decrypt bytes (was the password/key incorrect?):\n {error}");
-                return Err(());
+                return Err(error);
             }
         };
-        Ok(decrypted_bytes)
     }
 
+    if tried {
+        // A modified salt or KDF parameter derives a different key, so it can't be told apart from an incorrect password
+        warn!("If the password or key is correct, the salt or key derivation parameters of the key slots may have been modified");
+        return Err(Error::WrongPassword);
+    }
+
+    // None of the key slots can be decrypted with what we were given (I.E. a recovery key for a file without one), so list what would work
+    let mut needed: Vec<&str> = Vec::new();
+    for (_index, recipient) in recipients() {
+        if !needed.contains(&recipient.description()) {
+            needed.push(recipient.description());
+        }
+    }
+    warn!("The input file can only be decrypted with {}, which wasn't given", needed.join(" or "));
+    Err(Error::WrongPassword)
+}
+
+/// Computes the header MAC, which authenticates the whole header with the file key
+/// 
+/// # Arguments
+/// * `file_key` - The key of the file
+/// * `header_bytes` - The serialized header. Its last HEADER_MAC_SIZE bytes (the MAC itself) are ignored
+pub(crate) fn header_mac(file_key: &[u8; 32], header_bytes: &[u8]) -> Result<[u8; HEADER_MAC_SIZE]> {
+    keyed_hash(file_key, HEADER_MAC_MESSAGE, &header_bytes[..header_bytes.len() - HEADER_MAC_SIZE])
+}
+
+/// Makes sure that the header MAC matches the file key, in constant time
+pub(crate) fn verify_header_mac(file_key: &[u8; 32], header_bytes: &[u8]) -> Result<()> {
+    let mut mac = match <Blake2bMac256 as Mac>::new_from_slice(file_key) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(Error::Crypto(format!("Failed to compute the header MAC: {error}")));
+        }
+    };
+    mac.update(HEADER_MAC_MESSAGE);
+    mac.update(&header_bytes[..header_bytes.len() - HEADER_MAC_SIZE]);
+    match mac.verify_slice(&header_bytes[header_bytes.len() - HEADER_MAC_SIZE..]) {
+        Ok(_resp) => Ok(()),
+        Err(_error) => Err(Error::Corrupted("The header of the encrypted file was modified".to_string()))
+    }
 }
 
+/// Creates the cryptor that encrypts or decrypts the blocks, using the key derived from the file key
+/// 
+/// # Notes
+/// The core of the header is authenticated along with every block, so that any changes to it are detected
+fn payload_cryptor(file_key: &[u8; 32], cipher: Cipher, nonce: Option<[u8; MAX_NONCE_PREFIX_SIZE]>, header_core: &[u8]) -> Result<Cryptor> {
+    let mut payload_key = match keyed_hash(file_key, PAYLOAD_KEY_MESSAGE, &[]) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(error);
+        }
+    };
+    let cryptor = Cryptor::new(cipher, payload_key, nonce);
+
+    // Zeroize the payload_key for security
+    payload_key.zeroize();
+
+    let mut cryptor = match cryptor {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(error);
+        }
+    };
+    cryptor.set_associated_data(header_core);
+
+    Ok(cryptor)
+}
 
+/// Generates a random file key, encrypts it for everyone in the credentials, and builds the header of the encrypted file
+/// 
+/// # Arguments
+/// * `credentials` - The password and/or key file, and the public keys of the recipients
+/// * `options` - The cipher, key derivation parameters and compression algorithm to use
+/// 
+/// # Returns
+/// A tuple containing the cryptor (which already authenticates the header) and the header bytes that must be written before the first block
+fn prepare_encryption(credentials: Credentials, options: &EncryptionOptions) -> Result<(Cryptor, Vec<u8>)> {
+    // Generate the file key, and encrypt it for everyone who should be able to decrypt the file
+    let (mut file_key, slots) = match prepare_key_slots(&credentials, options) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(error);
+        }
+    };
 
-// AES256 Encryption - Consider adding the ability to utilize AES256 instead of ChaCha20Poly1305 in the future
-// let key = output_key_material.clone();
-// let cipher = Aes256GcmSiv::new_from_slice(&key).unwrap();
-// let nonce = Nonce::from_slice(b"unique nonce");
-// let encrypted_message = cipher.encrypt(&nonce, plaintext_message.as_ref()).unwrap();
-// let decrypted_message = cipher.decrypt(&nonce, encrypted_message.as_ref()).unwrap();
+    // Build the header, leaving the MAC empty until we know the rest of the header
+    // It contains everything we need to decrypt the data later (except the credentials)
+    let mut nonce = [0u8; MAX_NONCE_PREFIX_SIZE];
+    OsRng.fill_bytes(&mut nonce[..options.cipher.nonce_prefix_size()]);
+    let mut header = Header {
+        cipher: options.cipher,
+        compression: options.compression,
+        block_size: BLOCK_SIZE as u32,
+        nonce,
+        slots,
+        mac: [0u8; HEADER_MAC_SIZE]
+    };
+    let result = match header_mac(&file_key, &header.to_bytes()) {
+        Ok(resp) => {
+            header.mac = resp;
+            let header_bytes = header.to_bytes();
+            match payload_cryptor(&file_key, header.cipher, Some(header.nonce), &header_bytes[..HEADER_CORE_SIZE]) {
+                Ok(resp) => Ok((resp, header_bytes)),
+                Err(error) => Err(error)
+            }
+        },
+        Err(error) => Err(error)
+    };
+
+    // Zeroize the file_key for security
+    file_key.zeroize();
+
+    result
+}
+
+/// Parses the header of an encrypted file, and decrypts the file key with the credentials
+/// 
+/// # Arguments
+/// * `header_bytes` - The whole header, read from the start of the encrypted file
+/// * `credentials` - The password and/or key file you used to encrypt the file, or the private key of one of the recipients
+/// 
+/// # Returns
+/// A tuple containing the cryptor (which already authenticates the header) and the parsed header
+/// 
+/// # Notes
+/// This fails with an 'Incorrect password or key' error if none of the key slots in the header can be decrypted, without decrypting any blocks
+fn prepare_decryption(header_bytes: &[u8], credentials: Credentials) -> Result<(Cryptor, Header)> {
+    let header = match Header::from_bytes(header_bytes) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(error);
+        }
+    };
+
+    // Decrypt the file key
+    let (_slot, mut file_key) = match find_file_key(&header, &credentials) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(error);
+        }
+    };
+
+    // Make sure that the header wasn't modified, then initialize the cryptor
+    let result = match verify_header_mac(&file_key, header_bytes) {
+        Ok(_resp) => payload_cryptor(&file_key, header.cipher, Some(header.nonce), &header_bytes[..HEADER_CORE_SIZE]),
+        Err(error) => Err(error)
+    };
+
+    // Zeroize the file_key for security
+    file_key.zeroize();
+
+    match result {
+        Ok(resp) => Ok((resp, header)),
+        Err(error) => Err(error)
+    }
+}
+
+/// Reads the whole header from the start of an encrypted file
+/// 
+/// # Notes
+/// The size of the header depends on the number of key slots, so the start of it is read and validated first
+pub(crate) fn read_header<R: Read>(reader: &mut R) -> Result<Vec<u8>> {
+    let mut header_bytes = vec![0u8; HEADER_PREFIX_SIZE];
+    let num_header_bytes_read = match read_fully(reader, &mut header_bytes) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(Error::io("Failed to read the header", error));
+        }
+    };
+    let header_size = match Header::size_from_prefix(&header_bytes[..num_header_bytes_read]) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(error);
+        }
+    };
+
+    // Read the rest of the header (the key slots and the MAC)
+    header_bytes.resize(header_size, 0);
+    match read_fully(reader, &mut header_bytes[HEADER_PREFIX_SIZE..]) {
+        Ok(num_bytes_read) if num_bytes_read == header_size - HEADER_PREFIX_SIZE => Ok(header_bytes),
+        Ok(_num_bytes_read) => Err(Error::Corrupted("The header of the input file is truncated".to_string())),
+        Err(error) => Err(Error::io("Failed to read the header", error))
+    }
+}
+
+/// Encrypts a a file using a plaintext password and/or a key file
+/// 
+/// # Arguments
+/// * `input_file` - The location of the file you want to encrypt
+/// * `output_file` - The location of the output file
+/// * `credentials` - The password (It will be hashed using the Argon2id algorithm) and/or key file that you want use. A `String` is used as a password
+/// * `options` - The cipher, key derivation parameters and compression algorithm to use. These are stored in the header of the output file
+pub(crate) fn encrypt_file<T: AsRef<Path>, C: Into<Credentials>>(input_file: T, output_file: T, credentials: C, options: EncryptionOptions) -> Result<()> {
+    // Try to open the input and output files
+    let input = match open_input_file(&input_file) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(error);
+        }
+    };
+    let output = match create_output_file(&output_file) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(error);
+        }
+    };
+
+    // Everything written to the encrypting writer is compressed (if requested) and encrypted before it reaches the output file
+    let mut encrypting_writer = match EncryptingWriter::new(BufWriter::new(output), credentials, options) {
+        Ok(resp) => resp,
+        Err(error) => {
+            let _ = delete_file(&output_file);
+            return Err(error);
+        }
+    };
+
+    // Encrypt the input file, then encrypt the final block
+    match std::io::copy(&mut BufReader::new(input), &mut encrypting_writer) {
+        Ok(_resp) => {},
+        Err(error) => {
+            let _ = delete_file(&output_file);
+            return Err(Error::io("Failed to encrypt the input file", error));
+        }
+    };
+    match encrypting_writer.finish() {
+        Ok(_resp) => {},
+        Err(error) => {
+            let _ = delete_file(&output_file);
+            return Err(error);
+        }
+    };
+
+    // Return an ok result
+    Ok(())
+}
+
+/// Decrypts a a file using a plaintext password and/or a key file
+/// 
+/// # Arguments
+/// * `input_file` - The location of the encrypted file
+/// * `output_file` - The location where you want the output file
+/// * `credentials` - The password and/or key file you used to encrypt the file. A `String` is used as a password
+/// 
+/// # Notes
+/// The password will be hashed using the Argon2id algorithm and the salt that was stored in the file during the initial encryption process
+pub(crate) fn decrypt_file<T: AsRef<Path>, C: Into<Credentials>>(input_file: T, output_file: T, credentials: C) -> Result<()> {
+    // Try to open the input file
+    let input = match open_input_file(&input_file) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(error);
+        }
+    };
+
+    // Read the header and make sure that the password is correct before we touch the output file
+    let mut decrypting_reader = match DecryptingReader::new(BufReader::new(input), credentials) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(explain_modified_header(&input_file, error));
+        }
+    };
+
+    // Now that we know the password is correct, create the output file
+    let mut output = match create_output_file(&output_file) {
+        Ok(resp) => BufWriter::new(resp),
+        Err(error) => {
+            return Err(error);
+        }
+    };
+
+    // Decrypt the file, and make sure that we don't leave partially decrypted (or unauthenticated) data behind if we fail
+    match std::io::copy(&mut decrypting_reader, &mut output) {
+        Ok(_resp) => {},
+        Err(error) => {
+            let _ = delete_file(&output_file);
+            return Err(Error::io("Failed to decrypt the input file", error));
+        }
+    };
+    match output.flush() {
+        Ok(_resp) => {},
+        Err(error) => {
+            let _ = delete_file(&output_file);
+            return Err(Error::io("Failed to write bytes to output file", error));
+        }
+    };
+
+    Ok(())
+}
+
+/// Decrypts a file that was encrypted by arch-crypt 1.0, before encrypted files had a header
+/// 
+/// # Arguments
+/// * `input_file` - The location of the encrypted file
+/// * `output_file` - The location where you want the output file
+/// * `plaintext_password` - The password you used to encrypt the file
+/// 
+/// # Notes
+/// arch-crypt 1.0 encrypted every block with the same nonce. These files can still be decrypted, but they should be re-encrypted.
+/// The first block is decrypted before the output file is created, so an incorrect password returns `Error::WrongPassword` without leaving an empty output file behind
+pub fn decrypt_legacy_file<T: AsRef<Path>>(input_file: T, output_file: T, mut plaintext_password: String) -> Result<()> {
+    warn!("Decrypting a file in the legacy format (one nonce for every block). Consider re-encrypting it");
+
+    // Try to open the input file
+    let mut input = match open_input_file(&input_file) {
+        Ok(resp) => BufReader::new(resp),
+        Err(error) => {
+            plaintext_password.zeroize();
+            return Err(error);
+        }
+    };
+
+    // Read the salt and the nonce from the start of the input file
+    let mut salt_and_nonce = [0u8; 44];
+    match read_fully(&mut input, &mut salt_and_nonce) {
+        Ok(44) => {},
+        Ok(_resp) => {
+            plaintext_password.zeroize();
+            return Err(Error::Corrupted("The input file is too short to be a file encrypted by arch-crypt 1.0".to_string()));
+        },
+        Err(error) => {
+            plaintext_password.zeroize();
+            return Err(Error::io("Failed to read bytes from input file", error));
+        }
+    };
+    let mut salt = [0u8; 32];
+    let mut nonce = [0u8; 12];
+    salt.copy_from_slice(&salt_and_nonce[..32]);
+    nonce.copy_from_slice(&salt_and_nonce[32..]);
+
+    // Hash plaintext_password into a 256bit key
+    // Note: arch-crypt 1.0 used the default parameters of the argon2 crate, which KdfParams::default() matches
+    let hashed_password = hash_password(&plaintext_password, Some(salt), KdfParams::default());
+
+    // Zeroize the plaintext_password for security
+    plaintext_password.zeroize();
+
+    let (mut encryption_key, _salt) = match hashed_password {
+        Ok(resp) => {
+            resp
+        },
+        Err(error) => {
+            return Err(error);
+        }
+    };
+
+    // Initialize the cryptor
+    let cryptor = LegacyCryptor::new(encryption_key, nonce);
+
+    // Zeroize the encryption_key for security
+    encryption_key.zeroize();
+
+    // Legacy files don't have a key check value, so decrypting the first block is the only way to check the password
+    // Note: A modified first block can't be told apart from an incorrect password
+    let mut read_file_buffer = [0u8; BLOCK_SIZE + 16]; // We must read 16 extra bytes for the Poly1305 checksum
+    let num_bytes_read = match read_fully(&mut input, &mut read_file_buffer) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(Error::io("Failed to read bytes from input file", error));
+        }
+    };
+    let first_block = if num_bytes_read == 0 {
+        Vec::new()
+    } else {
+        match cryptor.decrypt_bytes(&read_file_buffer[..num_bytes_read]) {
+            Ok(resp) => resp,
+            Err(_error) => {
+                return Err(Error::WrongPassword);
+            }
+        }
+    };
+
+    // Now that we know the password is correct, create the output file
+    let mut output = match create_output_file(&output_file) {
+        Ok(resp) => BufWriter::new(resp),
+        Err(error) => {
+            return Err(error);
+        }
+    };
+
+    // Decrypt the rest of the input file, and make sure that we don't leave partially decrypted data behind if we fail
+    match decrypt_legacy_blocks(&cryptor, &first_block, &mut input, &mut output) {
+        Ok(_resp) => Ok(()),
+        Err(error) => {
+            let _ = delete_file(&output_file);
+            Err(error)
+        }
+    }
+}
+
+// Writes the first block of a legacy file, then decrypts the rest of the input in (BLOCK_SIZE + 16) chunks
+fn decrypt_legacy_blocks<R: Read, W: Write>(cryptor: &LegacyCryptor, first_block: &[u8], input: &mut R, output: &mut W) -> Result<()> {
+    let mut decrypted_bytes = first_block.to_vec();
+    let mut read_file_buffer = [0u8; BLOCK_SIZE + 16]; // We must read 16 extra bytes for the Poly1305 checksum
+    loop {
+        match output.write_all(&decrypted_bytes) {
+            Ok(_resp) => {},
+            Err(error) => {
+                return Err(Error::io("Failed to write bytes to output file", error));
+            }
+        };
+
+        // Try to read bytes from the input file, and stop at the end of it
+        let num_bytes_read = match read_fully(input, &mut read_file_buffer) {
+            Ok(0) => break,
+            Ok(resp) => resp,
+            Err(error) => {
+                return Err(Error::io("Failed to read bytes from input file", error));
+            }
+        };
+
+        // Decrypt the bytes
+        decrypted_bytes = match cryptor.decrypt_bytes(&read_file_buffer[..num_bytes_read]) {
+            Ok(resp) => {
+                resp
+            },
+            Err(error) => {
+                return Err(error);
+            }
+        };
+    }
+
+    match output.flush() {
+        Ok(_resp) => Ok(()),
+        Err(error) => Err(Error::io("Failed to write bytes to output file", error))
+    }
+}
+
+/// Derives the key of a segment from the encryption key
+/// 
+/// # Notes
+/// Every segment gets its own key, so the amount of data encrypted with one key (and one nonce prefix) stays small, no matter how large the file is
+fn derive_segment_key(key: &[u8; 32], segment: u64) -> Result<[u8; 32]> {
+    let mut mac = match <Blake2bMac256 as Mac>::new_from_slice(key) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(Error::Crypto(format!("Failed to derive the segment key: {error}")));
+        }
+    };
+    mac.update(SEGMENT_KEY_MESSAGE);
+    mac.update(&segment.to_le_bytes());
+    Ok(mac.finalize().into_bytes().into())
+}
+
+// Hashes a message with keyed BLAKE2b, after a constant that says what the hash is used for
+pub(crate) fn keyed_hash(key: &[u8], purpose: &[u8], message: &[u8]) -> Result<[u8; 32]> {
+    let mut mac = match <Blake2bMac256 as Mac>::new_from_slice(key) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(Error::Crypto(format!("Failed to derive the encryption key: {error}")));
+        }
+    };
+    mac.update(purpose);
+    mac.update(message);
+    Ok(mac.finalize().into_bytes().into())
+}
+
+/// Encrypts everything written to it, and writes the encrypted file (header and blocks) to the inner writer
+/// 
+/// # Notes
+/// If compression was requested, the bytes are compressed before they're encrypted.
+/// `finish` must be called after the last write to encrypt the final block. Dropping the writer without calling `finish` leaves the output truncated.
+/// Bytes are encrypted in blocks of 32 KiB, so `flush` only flushes the blocks that are already full. The rest is written by the next full block, or by `finish`
+pub struct EncryptingWriter<W: Write> {
+    inner: CompressingWriter<BlockEncryptor<W>>
+}
+
+impl<W: Write> EncryptingWriter<W> {
+    /// Returns an instance of `EncryptingWriter`, after writing the header to the inner writer
+    /// 
+    /// # Arguments
+    /// * `inner` - The writer that the encrypted bytes are written to
+    /// * `credentials` - The password (It will be hashed using the Argon2id algorithm) and/or key file that you want use. A `String` is used as a password
+    /// * `options` - The cipher, key derivation parameters and compression algorithm to use
+    pub fn new<C: Into<Credentials>>(mut inner: W, credentials: C, options: EncryptionOptions) -> Result<Self> {
+        // Derive the encryption key and build the header
+        let (cryptor, header_bytes) = match prepare_encryption(credentials.into(), &options) {
+            Ok(resp) => resp,
+            Err(error) => {
+                return Err(error);
+            }
+        };
+
+        // Write the header before any of the blocks
+        match inner.write_all(&header_bytes) {
+            Ok(_resp) => {},
+            Err(error) => {
+                return Err(Error::io("Failed to write the header", error));
+            }
+        };
+
+        // Compress the bytes before they're split into blocks and encrypted
+        let block_encryptor = BlockEncryptor {
+            inner,
+            cryptor,
+            buffer: Vec::with_capacity(BLOCK_SIZE),
+            block_index: 0
+        };
+        let compressing_writer = match CompressingWriter::new(block_encryptor, options.compression) {
+            Ok(resp) => resp,
+            Err(error) => {
+                return Err(Error::io("Failed to initialize the compressor", error));
+            }
+        };
+
+        Ok(Self {
+            inner: compressing_writer
+        })
+    }
+
+    /// Finishes compressing, encrypts the final block and flushes the inner writer
+    /// 
+    /// # Returns
+    /// Returns the inner writer
+    pub fn finish(self) -> Result<W> {
+        let block_encryptor = match self.inner.finish() {
+            Ok(resp) => resp,
+            Err(error) => {
+                return Err(Error::io("Failed to finish compressing", error));
+            }
+        };
+        block_encryptor.finish()
+    }
+
+    /// Returns a reference to the inner writer
+    pub fn get_ref(&self) -> &W {
+        &self.inner.get_ref().inner
+    }
+
+    /// Returns a mutable reference to the inner writer
+    /// 
+    /// # Notes
+    /// Writing to the inner writer directly will corrupt the encrypted file
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner.get_mut().inner
+    }
+}
+
+impl<W: Write> Write for EncryptingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Splits everything written to it into blocks, and encrypts them
+// Bytes are buffered until a full block is available. A block is only encrypted once we know whether it's the final block
+struct BlockEncryptor<W: Write> {
+    inner: W,
+    cryptor: Cryptor,
+    buffer: Vec<u8>,
+    block_index: u64
+}
+
+impl<W: Write> BlockEncryptor<W> {
+    // Encrypts the final block and flushes the inner writer
+    fn finish(mut self) -> Result<W> {
+        match self.write_block(true) {
+            Ok(_resp) => {},
+            Err(error) => {
+                return Err(Error::io("Failed to write the final block", error));
+            }
+        };
+        match self.inner.flush() {
+            Ok(_resp) => {},
+            Err(error) => {
+                return Err(Error::io("Failed to flush the output", error));
+            }
+        };
+        Ok(self.inner)
+    }
+
+    // Encrypts the buffered bytes as one block, and writes it to the inner writer
+    fn write_block(&mut self, last_block: bool) -> std::io::Result<()> {
+        let encrypted_bytes = match self.cryptor.encrypt_bytes(&self.buffer, self.block_index, last_block) {
+            Ok(resp) => resp,
+            Err(error) => {
+                return Err(error.into());
+            }
+        };
+        self.inner.write_all(&encrypted_bytes)?;
+        self.buffer.clear();
+
+        // Move on to the next block, making sure that we never reuse a block counter (and therefore a nonce)
+        if !last_block {
+            self.block_index = match self.cryptor.next_block_index(self.block_index) {
+                Ok(resp) => resp,
+                Err(error) => {
+                    return Err(error.into());
+                }
+            };
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for BlockEncryptor<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut remaining = buf;
+        while !remaining.is_empty() {
+            // The buffer is full and there's more data coming, so the buffered block isn't the final block
+            if self.buffer.len() == BLOCK_SIZE {
+                self.write_block(false)?;
+            }
+
+            let num_bytes = remaining.len().min(BLOCK_SIZE - self.buffer.len());
+            self.buffer.extend_from_slice(&remaining[..num_bytes]);
+            remaining = &remaining[num_bytes..];
+        }
+        Ok(buf.len())
+    }
+
+    // Note: Partial blocks can't be encrypted until we know if they're the final block, so this only flushes the inner writer
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reads an encrypted file (header and blocks) from the inner reader, and returns the decrypted bytes
+/// 
+/// # Notes
+/// Every block is authenticated before any of its bytes are returned, and the data is decompressed if it was compressed before encryption.
+/// If a block fails to decrypt (or the file was truncated), `read` returns an error.
+/// A block can only be decrypted once we know whether it's the final block, so the reader always reads one block ahead (or to the end of the input)
+pub struct DecryptingReader<R: Read> {
+    inner: DecompressingReader<BlockDecryptor<R>>,
+    finished: bool
+}
+
+impl<R: Read> DecryptingReader<R> {
+    /// Returns an instance of `DecryptingReader`, after reading the header from the inner reader and checking the password
+    /// 
+    /// # Arguments
+    /// * `inner` - The reader that the encrypted bytes are read from
+    /// * `credentials` - The password and/or key file you used to encrypt the file. A `String` is used as a password
+    pub fn new<C: Into<Credentials>>(mut inner: R, credentials: C) -> Result<Self> {
+        // Read and validate the header, and make sure that the credentials are correct
+        let header_bytes = match read_header(&mut inner) {
+            Ok(resp) => resp,
+            Err(error) => {
+                return Err(error);
+            }
+        };
+        let (cryptor, header) = match prepare_decryption(&header_bytes, credentials.into()) {
+            Ok(resp) => resp,
+            Err(error) => {
+                return Err(error);
+            }
+        };
+
+        let mut block_decryptor = BlockDecryptor {
+            inner,
+            cryptor,
+            block_size: header.block_size as usize,
+            next_block: Vec::new(),
+            plaintext: Vec::new(),
+            plaintext_position: 0,
+            block_index: 0,
+            finished: false
+        };
+
+        // Read the first block ahead of time, so we always know whether the current block is the final block
+        match block_decryptor.read_next_block() {
+            Ok(_resp) => {},
+            Err(error) => {
+                return Err(Error::io("Failed to read the first block", error));
+            }
+        };
+        if block_decryptor.next_block.is_empty() {
+            return Err(Error::Corrupted("The encrypted file is truncated (it doesn't contain any blocks)".to_string()));
+        }
+
+        // Decompress the bytes after they're decrypted
+        let decompressing_reader = match DecompressingReader::new(block_decryptor, header.compression) {
+            Ok(resp) => resp,
+            Err(error) => {
+                return Err(Error::io("Failed to initialize the decompressor", error));
+            }
+        };
+
+        Ok(Self {
+            inner: decompressing_reader,
+            finished: false
+        })
+    }
+
+    /// Returns a reference to the inner reader
+    pub fn get_ref(&self) -> &R {
+        &self.inner.get_ref().inner
+    }
+
+    /// Returns a mutable reference to the inner reader
+    /// 
+    /// # Notes
+    /// Reading from the inner reader directly will cause decryption to fail
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner.get_mut().inner
+    }
+
+    // Makes sure that the decompressor used all of the decrypted bytes, and that we reached the final block
+    // The decompressor may stop reading once it reaches the end of the compressed data, which would skip the checks for truncation
+    fn verify_end(&mut self) -> std::io::Result<()> {
+        let mut extra_byte = [0u8; 1];
+        if self.inner.get_mut().read(&mut extra_byte)? != 0 {
+            return Err(Error::Corrupted("The encrypted file has unexpected data after the end of the compressed data".to_string()).into());
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let num_bytes = self.inner.read(buf)?;
+        if num_bytes == 0 && !buf.is_empty() && !self.finished {
+            self.verify_end()?;
+            self.finished = true;
+        }
+        Ok(num_bytes)
+    }
+}
+
+// Reads blocks from the inner reader, and decrypts them
+struct BlockDecryptor<R: Read> {
+    inner: R,
+    cryptor: Cryptor,
+    block_size: usize,
+    next_block: Vec<u8>,
+    plaintext: Vec<u8>,
+    plaintext_position: usize,
+    block_index: u64,
+    finished: bool
+}
+
+impl<R: Read> BlockDecryptor<R> {
+    // Reads the next encrypted block from the inner reader into next_block. An empty block means that we reached the end of the input
+    fn read_next_block(&mut self) -> std::io::Result<()> {
+        self.next_block.resize(self.block_size + 16, 0); // We must read 16 extra bytes for the Poly1305 checksum
+        let num_bytes_read = read_fully(&mut self.inner, &mut self.next_block)?;
+        self.next_block.truncate(num_bytes_read);
+        Ok(())
+    }
+
+    // Decrypts the next block into the plaintext buffer
+    fn decrypt_next_block(&mut self) -> std::io::Result<()> {
+        // Swap the block we read ahead of time with the one after it
+        let encrypted_block = std::mem::take(&mut self.next_block);
+        let last_block = if encrypted_block.len() < self.block_size + 16 {
+            true
+        } else {
+            self.read_next_block()?;
+            self.next_block.is_empty()
+        };
+
+        // Decrypt the block. If the block fails authentication, figure out why before giving up
+        self.plaintext = match self.cryptor.decrypt_bytes(&encrypted_block, self.block_index, last_block) {
+            Ok(resp) => resp,
+            Err(Error::Corrupted(_)) => {
+                return Err(self.cryptor.diagnose_block(&encrypted_block, self.block_index, last_block).into());
+            },
+            Err(error) => {
+                return Err(error.into());
+            }
+        };
+        self.plaintext_position = 0;
+
+        // Stop after the final block, otherwise move on to the next block
+        if last_block {
+            self.finished = true;
+        } else {
+            self.block_index = match self.cryptor.next_block_index(self.block_index) {
+                Ok(resp) => resp,
+                Err(error) => {
+                    return Err(error.into());
+                }
+            };
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for BlockDecryptor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        // Decrypt blocks until we have some plaintext to return (the final block may be empty)
+        while self.plaintext_position == self.plaintext.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            self.decrypt_next_block()?;
+        }
+
+        let num_bytes = buf.len().min(self.plaintext.len() - self.plaintext_position);
+        buf[..num_bytes].copy_from_slice(&self.plaintext[self.plaintext_position..self.plaintext_position + num_bytes]);
+        self.plaintext_position += num_bytes;
+        Ok(num_bytes)
+    }
+}
+
+// Reads from a reader until the buffer is full or the end of the input is reached
+fn read_fully<R: Read>(reader: &mut R, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut total_num_bytes_read = 0;
+    while total_num_bytes_read < buffer.len() {
+        match reader.read(&mut buffer[total_num_bytes_read..]) {
+            Ok(0) => break,
+            Ok(num_bytes_read) => {
+                total_num_bytes_read += num_bytes_read;
+            },
+            Err(error) if error.kind() == ErrorKind::Interrupted => {},
+            Err(error) => {
+                return Err(error);
+            }
+        }
+    }
+    Ok(total_num_bytes_read)
+}
+
+/// The STREAM construction wrapped around each of the ciphers we support
+enum CipherStream {
+    ChaCha20Poly1305(StreamBE32<ChaCha20Poly1305>),
+    XChaCha20Poly1305(StreamBE32<XChaCha20Poly1305>),
+    Aes256GcmSiv(Box<StreamBE32<Aes256GcmSiv>>)
+}
+
+impl CipherStream {
+    // Note: GenericArray::from_slice panics if the nonce prefix has the wrong size, so it's always sliced to cipher.nonce_prefix_size()
+    fn new(cipher: Cipher, key: &[u8; 32], nonce: &[u8]) -> Self {
+        match cipher {
+            Cipher::ChaCha20Poly1305 => Self::ChaCha20Poly1305(StreamBE32::from_aead(ChaCha20Poly1305::new(key.into()), GenericArray::from_slice(nonce))),
+            Cipher::XChaCha20Poly1305 => Self::XChaCha20Poly1305(StreamBE32::from_aead(XChaCha20Poly1305::new(key.into()), GenericArray::from_slice(nonce))),
+            Cipher::Aes256GcmSiv => Self::Aes256GcmSiv(Box::new(StreamBE32::from_aead(Aes256GcmSiv::new(key.into()), GenericArray::from_slice(nonce))))
+        }
+    }
+
+    fn encrypt(&self, block_index: u32, last_block: bool, payload: Payload) -> chacha20poly1305::aead::Result<Vec<u8>> {
+        match self {
+            Self::ChaCha20Poly1305(stream) => stream.encrypt(block_index, last_block, payload),
+            Self::XChaCha20Poly1305(stream) => stream.encrypt(block_index, last_block, payload),
+            Self::Aes256GcmSiv(stream) => stream.encrypt(block_index, last_block, payload)
+        }
+    }
+
+    fn decrypt(&self, block_index: u32, last_block: bool, payload: Payload) -> chacha20poly1305::aead::Result<Vec<u8>> {
+        match self {
+            Self::ChaCha20Poly1305(stream) => stream.decrypt(block_index, last_block, payload),
+            Self::XChaCha20Poly1305(stream) => stream.decrypt(block_index, last_block, payload),
+            Self::Aes256GcmSiv(stream) => stream.decrypt(block_index, last_block, payload)
+        }
+    }
+}
+
+/// Used to encrypt/decrypt blocks of bytes with a given cipher and key, and optionally a nonce prefix
+/// 
+/// # Notes
+/// Blocks are encrypted using the STREAM construction, so every block gets its own nonce made from the nonce prefix, the block index and a 'last block' flag.
+/// Ciphers that rekey (see `Cipher::rekeys_segments`) split the file into segments of SEGMENT_SIZE blocks, and every segment is encrypted with its own key
+pub(crate) struct Cryptor {
+    cipher: Cipher,
+    // The stream of the segment that was used last (or of the whole file, if the cipher doesn't rekey)
+    stream: CipherStream,
+    // The segment that the stream belongs to
+    segment: u64,
+    // Only kept for ciphers that rekey, so we can derive the keys for the other segments
+    key: Option<[u8; 32]>,
+    nonce: [u8; MAX_NONCE_PREFIX_SIZE],
+    associated_data: Vec<u8>
+}
+
+impl Cryptor {
+    /// Returns an instance `Cryptor` with the provided arguments
+    ///
+    /// # Arguments
+    /// * `cipher` - The AEAD cipher used for encryption/decryption
+    /// * `key` - The 256-bit key used for encryption/decryption
+    /// * `input_nonce` - Optional, allows you to specicify a nonce prefix
+    /// 
+    /// # Notes
+    /// Specifying an input_nonce is useful when decrypting a file. You will need to use the same nonce prefix that was used to encrypt the data.
+    /// Only the first `cipher.nonce_prefix_size()` bytes of the nonce prefix are used
+    pub fn new(cipher: Cipher, mut key: [u8; 32], input_nonce: Option<[u8; MAX_NONCE_PREFIX_SIZE]>) -> Result<Self> {
+        // Use provided nonce prefix or generate a random one
+        // The bytes that the cipher doesn't use are left as zeroes
+        let nonce_prefix_size = cipher.nonce_prefix_size();
+        let mut nonce = [0u8; MAX_NONCE_PREFIX_SIZE];
+        match input_nonce {
+            Some(resp) => {
+                nonce = resp;
+            },
+            None => {
+                OsRng.fill_bytes(&mut nonce[..nonce_prefix_size]);
+            },
+        };
+
+        // Ciphers that rekey never use the key directly, every segment (including the first) gets its own key
+        let (stream, segment_key) = if cipher.rekeys_segments() {
+            let mut first_segment_key = match derive_segment_key(&key, 0) {
+                Ok(resp) => resp,
+                Err(error) => {
+                    key.zeroize();
+                    return Err(error);
+                }
+            };
+            let stream = CipherStream::new(cipher, &first_segment_key, &nonce[..nonce_prefix_size]);
+            first_segment_key.zeroize();
+            (stream, Some(key))
+        } else {
+            (CipherStream::new(cipher, &key, &nonce[..nonce_prefix_size]), None)
+        };
+
+        // Zeroize the encryption key for security (a copy is kept in segment_key for ciphers that rekey, which is zeroized on drop)
+        key.zeroize();
+        
+        // Return Cryptor instance
+        Ok(Self {
+            cipher,
+            stream,
+            segment: 0,
+            key: segment_key,
+            nonce,
+            associated_data: Vec::new()
+        })
+    }
+
+    /// Sets the associated data that is authenticated (but not encrypted) along with every block
+    /// 
+    /// # Arguments
+    /// * `associated_data` - The bytes to authenticate, usually the header of the file
+    pub fn set_associated_data(&mut self, associated_data: &[u8]) {
+        self.associated_data = associated_data.to_vec();
+    }
+
+    /// Returns the index of the block that follows `block_index`
+    /// 
+    /// # Notes
+    /// The block index is part of the nonce, so running out of indices means that we can't safely encrypt any more blocks.
+    /// Ciphers that don't rekey can encrypt up to 2^32 blocks, and ciphers that rekey can encrypt up to 2^64 blocks
+    pub fn next_block_index(&self, block_index: u64) -> Result<u64> {
+        let max_block_index = if self.cipher.rekeys_segments() { u64::MAX } else { u32::MAX as u64 };
+        match block_index.checked_add(1) {
+            Some(resp) if resp < max_block_index => Ok(resp),
+            _ => Err(Error::Crypto("The file is too large, we ran out of unique nonces".to_string()))
+        }
+    }
+
+    // Encrypts or decrypts a block with the stream of the segment it belongs to
+    // Returns None if the cipher failed (I.E. the block failed authentication)
+    fn apply_stream(&mut self, encrypt: bool, buffer: &[u8], block_index: u64, last_block: bool) -> Result<Option<Vec<u8>>> {
+        // Work out which segment the block is in, and where it is in that segment
+        let (segment, segment_block_index) = match self.key {
+            Some(_) => (block_index / SEGMENT_SIZE, (block_index % SEGMENT_SIZE) as u32),
+            None => (0, block_index as u32)
+        };
+
+        // Derive the stream of the segment, unless we already have it
+        // Blocks are encrypted and decrypted in order, so this only happens once every SEGMENT_SIZE blocks
+        if let (true, Some(key)) = (segment != self.segment, &self.key) {
+            let mut segment_key = match derive_segment_key(key, segment) {
+                Ok(resp) => resp,
+                Err(error) => {
+                    return Err(error);
+                }
+            };
+            self.stream = CipherStream::new(self.cipher, &segment_key, &self.nonce[..self.cipher.nonce_prefix_size()]);
+            self.segment = segment;
+            segment_key.zeroize();
+        }
+
+        let payload = Payload { msg: buffer, aad: &self.associated_data };
+        let result = if encrypt {
+            self.stream.encrypt(segment_block_index, last_block, payload)
+        } else {
+            self.stream.decrypt(segment_block_index, last_block, payload)
+        };
+        match result {
+            Ok(resp) => Ok(Some(resp)),
+            Err(_error) => Ok(None)
+        }
+    }
+
+    /// Encrypts a given slice of bytes
+    /// 
+    /// # Arguments
+    /// * `buffer` - The buffer of bytes that you want encrypted
+    /// * `block_index` - The position of the block in the file
+    /// * `last_block` - Whether this is the final block of the file
+    /// 
+    /// # Returns
+    /// Returns a Vector of bytes (Vec\<u8>)
+    pub fn encrypt_bytes(&mut self, buffer: &[u8], block_index: u64, last_block: bool) -> Result<Vec<u8>> {
+        let encrypted_bytes = match self.apply_stream(true, buffer, block_index, last_block) {
+            Ok(Some(resp)) => {
+                resp
+            },
+            Ok(None) => {
+                return Err(Error::Crypto(format!("Failed to encrypt block {block_index}")));
+            },
+            Err(error) => {
+                return Err(error);
+            }
+        };
+        Ok(encrypted_bytes)
+    }
+
+    /// Decrypts a given slice of bytes
+    /// 
+    /// # Arguments
+    /// * `buffer` - The buffer of bytes that you want decrypted
+    /// * `block_index` - The position of the block in the file
+    /// * `last_block` - Whether this is the final block of the file
+    /// 
+    /// # Returns
+    /// Returns a Vector of bytes (Vec\<u8>), or `Error::Corrupted` if the block failed authentication.
+    /// Use `diagnose_block` to find out why
+    pub fn decrypt_bytes(&mut self, buffer: &[u8], block_index: u64, last_block: bool) -> Result<Vec<u8>> {
+        let decrypted_bytes = match self.apply_stream(false, buffer, block_index, last_block) {
+            Ok(Some(resp)) => {
+                resp
+            },
+            Ok(None) => {
+                return Err(Error::Corrupted(format!("Failed to decrypt block {block_index}")));
+            },
+            Err(error) => {
+                return Err(error);
+            }
+        };
+        Ok(decrypted_bytes)
+    }
+
+    // Returns true if a block decrypts at the given position
+    fn block_decrypts_at(&mut self, buffer: &[u8], block_index: u64, last_block: bool) -> bool {
+        matches!(self.apply_stream(false, buffer, block_index, last_block), Ok(Some(_)))
+    }
+
+    /// Works out why a block failed to decrypt
+    /// 
+    /// # Returns
+    /// Returns an `Error::Corrupted` that describes what happened to the block
+    /// 
+    /// # Arguments
+    /// * `buffer` - The buffer of bytes that failed to decrypt
+    /// * `block_index` - The position the block was found at
+    /// * `last_block` - Whether the block was found at the end of the file
+    /// 
+    /// # Notes
+    /// Every block is bound to its position and the final block is marked, so we can try decrypting the block as if it were somewhere else.
+    /// This is only done after a block fails, so the extra work doesn't slow down decryption of valid files
+    pub fn diagnose_block(&mut self, buffer: &[u8], block_index: u64, last_block: bool) -> Error {
+        // The block is fine, but it isn't the final block, so the end of the file is missing
+        if last_block && self.block_decrypts_at(buffer, block_index, false) {
+            return Error::Corrupted("The encrypted file is truncated (the final block is missing)".to_string());
+        }
+
+        // The block is the final block, but there's more data after it
+        if !last_block && self.block_decrypts_at(buffer, block_index, true) {
+            return Error::Corrupted("The encrypted file has unexpected data after the final block".to_string());
+        }
+
+        // The block belongs somewhere else in the file
+        for other_index in block_index.saturating_sub(REORDER_SEARCH_WINDOW)..block_index.saturating_add(REORDER_SEARCH_WINDOW) {
+            if other_index == block_index {
+                continue;
+            }
+            if self.block_decrypts_at(buffer, other_index, false) || self.block_decrypts_at(buffer, other_index, true) {
+                return Error::Corrupted(format!("Block {other_index} was found at position {block_index}, the blocks of the encrypted file were reordered"));
+            }
+        }
+
+        // The password was already checked, so if the first block can't be decrypted at all, the header or the block itself was modified
+        // Note: Data appended to a short final block ends up in the same read, so we can't tell it apart from corruption
+        if block_index == 0 {
+            Error::Corrupted("The header or the first block of the encrypted file was modified".to_string())
+        } else if last_block {
+            Error::Corrupted("The final block was modified or corrupted, or data was appended to the encrypted file".to_string())
+        } else {
+            Error::Corrupted(format!("Block {block_index} was modified or corrupted"))
+        }
+    }
+
+}
+
+// Zeroize the copy of the encryption key that ciphers which rekey hold on to
+impl Drop for Cryptor {
+    fn drop(&mut self) {
+        if let Some(key) = &mut self.key {
+            key.zeroize();
+        }
+    }
+}
+
+/// Used to decrypt files written by older versions of arch-crypt, which used the same nonce for every block
+struct LegacyCryptor {
+    cipher: ChaChaPoly1305<ChaCha20, U12>,
+    nonce: [u8; 12]
+}
+
+impl LegacyCryptor {
+    /// Returns an instance `LegacyCryptor` with the provided key and nonce
+    fn new(mut key: [u8; 32], nonce: [u8; 12]) -> Self {
+        // Create ChaCha20Poly1305 cipher using our 256bit key
+        let cipher = ChaCha20Poly1305::new(&key.into());
+
+        // Zeroize the encryption key for security
+        key.zeroize();
+
+        Self {
+            cipher,
+            nonce
+        }
+    }
+
+    /// Decrypts a given slice of bytes
+    fn decrypt_bytes(&self, buffer: &[u8]) -> Result<Vec<u8>> {
+        let decrypted_bytes = match self.cipher.decrypt(&self.nonce.into(), buffer) {
+            Ok(resp) => {
+                resp
+            },
+            Err(_error) => {
+                return Err(Error::Corrupted("A block of the encrypted file was modified or corrupted".to_string()));
+            }
+        };
+        Ok(decrypted_bytes)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const PASSWORD: &str = "correct horse battery staple";
+
+    // The cheapest parameters Argon2id accepts, so the tests don't spend their time hashing the password
+    const TEST_KDF_PARAMS: KdfParams = KdfParams { memory_cost: 8, time_cost: 1, parallelism: 1 };
+
+    // Encrypts with a single key slot, so the blocks start at Header::size(1)
+    fn test_options(cipher: Cipher, compression: Compression) -> EncryptionOptions {
+        EncryptionOptions {
+            cipher,
+            kdf_params: TEST_KDF_PARAMS,
+            compression,
+            key_slots: 1
+        }
+    }
+
+    // Bytes that don't repeat within a block, so moving a block around always changes the plaintext
+    fn test_data(len: usize) -> Vec<u8> {
+        (0..len).map(|index| (index * 7 + index / 251) as u8).collect()
+    }
+
+    fn encrypt(data: &[u8], options: EncryptionOptions) -> Vec<u8> {
+        let mut writer = EncryptingWriter::new(Vec::new(), PASSWORD.to_string(), options).unwrap();
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn decrypt(encrypted: &[u8], password: &str) -> Result<Vec<u8>> {
+        let mut reader = match DecryptingReader::new(Cursor::new(encrypted), password.to_string()) {
+            Ok(resp) => resp,
+            Err(error) => {
+                return Err(error);
+            }
+        };
+        let mut plaintext = Vec::new();
+        match reader.read_to_end(&mut plaintext) {
+            Ok(_resp) => Ok(plaintext),
+            Err(error) => Err(Error::io("Failed to decrypt", error))
+        }
+    }
+
+    // Returns the message of an Error::Corrupted, and fails the test for anything else
+    fn corrupted_message(result: Result<Vec<u8>>) -> String {
+        match result {
+            Err(Error::Corrupted(message)) => message,
+            Err(error) => panic!("expected Error::Corrupted, got {error:?}"),
+            Ok(_resp) => panic!("expected Error::Corrupted, but decryption succeeded")
+        }
+    }
+
+    // Returns the range of bytes that holds the given encrypted block
+    fn block_range(block_index: usize) -> std::ops::Range<usize> {
+        let start = Header::size(1) + block_index * (BLOCK_SIZE + 16);
+        start..start + BLOCK_SIZE + 16
+    }
+
+    fn swap_blocks(encrypted: &mut [u8], first: usize, second: usize) {
+        let first_block = encrypted[block_range(first)].to_vec();
+        let second_block = encrypted[block_range(second)].to_vec();
+        encrypted[block_range(first)].copy_from_slice(&second_block);
+        encrypted[block_range(second)].copy_from_slice(&first_block);
+    }
+
+    #[test]
+    fn every_cipher_and_compression_round_trips() {
+        let data = test_data(3 * BLOCK_SIZE + 1000);
+        for cipher in [Cipher::ChaCha20Poly1305, Cipher::XChaCha20Poly1305, Cipher::Aes256GcmSiv] {
+            for compression in [Compression::None, Compression::Zstd, Compression::Snappy, Compression::Gzip, Compression::Xz] {
+                let encrypted = encrypt(&data, test_options(cipher, compression));
+                assert_eq!(decrypt(&encrypted, PASSWORD).unwrap(), data, "{cipher:?} with {compression:?} didn't round trip");
+
+                let encrypted = encrypt(&[], test_options(cipher, compression));
+                assert!(decrypt(&encrypted, PASSWORD).unwrap().is_empty(), "{cipher:?} with {compression:?} didn't round trip an empty file");
+            }
+        }
+    }
+
+    #[test]
+    fn dropping_the_final_block_is_reported_as_truncated() {
+        let mut encrypted = encrypt(&test_data(3 * BLOCK_SIZE + 100), test_options(Cipher::ChaCha20Poly1305, Compression::None));
+        encrypted.truncate(block_range(3).start);
+        assert_eq!(corrupted_message(decrypt(&encrypted, PASSWORD)), "The encrypted file is truncated (the final block is missing)");
+
+        encrypted.truncate(Header::size(1));
+        assert_eq!(corrupted_message(decrypt(&encrypted, PASSWORD)), "The encrypted file is truncated (it doesn't contain any blocks)");
+    }
+
+    #[test]
+    fn appended_bytes_are_reported() {
+        // The final block is full, so the appended bytes are read as a block of their own
+        let mut encrypted = encrypt(&test_data(2 * BLOCK_SIZE), test_options(Cipher::ChaCha20Poly1305, Compression::None));
+        encrypted.extend_from_slice(b"appended");
+        assert_eq!(corrupted_message(decrypt(&encrypted, PASSWORD)), "The encrypted file has unexpected data after the final block");
+
+        // The final block is short, so the appended bytes end up in the same read
+        let mut encrypted = encrypt(&test_data(BLOCK_SIZE + 100), test_options(Cipher::ChaCha20Poly1305, Compression::None));
+        encrypted.extend_from_slice(b"appended");
+        assert_eq!(corrupted_message(decrypt(&encrypted, PASSWORD)), "The final block was modified or corrupted, or data was appended to the encrypted file");
+    }
+
+    #[test]
+    fn swapped_blocks_are_reported_as_reordered() {
+        let mut encrypted = encrypt(&test_data(4 * BLOCK_SIZE + 100), test_options(Cipher::ChaCha20Poly1305, Compression::None));
+        swap_blocks(&mut encrypted, 1, 2);
+        assert_eq!(corrupted_message(decrypt(&encrypted, PASSWORD)), "Block 2 was found at position 1, the blocks of the encrypted file were reordered");
+    }
+
+    #[test]
+    fn a_modified_block_is_reported() {
+        let mut encrypted = encrypt(&test_data(4 * BLOCK_SIZE + 100), test_options(Cipher::ChaCha20Poly1305, Compression::None));
+        encrypted[block_range(2).start + 10] ^= 0x01;
+        assert_eq!(corrupted_message(decrypt(&encrypted, PASSWORD)), "Block 2 was modified or corrupted");
+    }
+
+    #[test]
+    fn a_modified_header_is_told_apart_from_a_wrong_password() {
+        let encrypted = encrypt(&test_data(BLOCK_SIZE + 100), test_options(Cipher::ChaCha20Poly1305, Compression::None));
+
+        // A byte of the nonce prefix, in the header core
+        let mut modified = encrypted.clone();
+        modified[20] ^= 0x01;
+        assert_eq!(corrupted_message(decrypt(&modified, PASSWORD)), "The header of the encrypted file was modified");
+
+        // The last byte of the header MAC
+        let mut modified = encrypted.clone();
+        modified[Header::size(1) - 1] ^= 0x01;
+        assert_eq!(corrupted_message(decrypt(&modified, PASSWORD)), "The header of the encrypted file was modified");
+
+        assert!(matches!(decrypt(&encrypted, "incorrect horse battery staple"), Err(Error::WrongPassword)));
+    }
+
+    #[test]
+    fn blocks_round_trip_across_a_segment_boundary() {
+        let data = test_data(3 * SEGMENT_SIZE as usize * BLOCK_SIZE + 100);
+        let encrypted = encrypt(&data, test_options(Cipher::XChaCha20Poly1305, Compression::None));
+        assert_eq!(decrypt(&encrypted, PASSWORD).unwrap(), data);
+
+        // The last block of the first segment and the first block of the second segment are still told apart
+        let mut reordered = encrypted.clone();
+        let boundary = SEGMENT_SIZE as usize;
+        swap_blocks(&mut reordered, boundary - 1, boundary);
+        assert_eq!(corrupted_message(decrypt(&reordered, PASSWORD)), format!("Block {boundary} was found at position {}, the blocks of the encrypted file were reordered", boundary - 1));
+    }
+
+    #[test]
+    fn odd_sized_writes_and_reads_round_trip() {
+        let data = test_data(3 * BLOCK_SIZE + 1000);
+        let chunk_sizes = [1, 7, 4093, BLOCK_SIZE + 1, 13];
+        for compression in [Compression::None, Compression::Zstd] {
+            // Write the data in chunks that never line up with a block, flushing along the way
+            let mut writer = EncryptingWriter::new(Vec::new(), PASSWORD.to_string(), test_options(Cipher::ChaCha20Poly1305, compression)).unwrap();
+            let mut remaining = data.as_slice();
+            for chunk_size in chunk_sizes.iter().cycle() {
+                if remaining.is_empty() {
+                    break;
+                }
+                let (chunk, rest) = remaining.split_at((*chunk_size).min(remaining.len()));
+                writer.write_all(chunk).unwrap();
+                writer.flush().unwrap();
+                remaining = rest;
+            }
+            let encrypted = writer.finish().unwrap();
+
+            // Read it back through a Cursor, into buffers that don't line up with a block either
+            let mut reader = DecryptingReader::new(Cursor::new(encrypted), PASSWORD.to_string()).unwrap();
+            let mut plaintext = Vec::new();
+            for chunk_size in chunk_sizes.iter().cycle() {
+                let mut buffer = vec![0u8; *chunk_size];
+                let num_bytes = reader.read(&mut buffer).unwrap();
+                if num_bytes == 0 {
+                    break;
+                }
+                plaintext.extend_from_slice(&buffer[..num_bytes]);
+            }
+            assert_eq!(plaintext, data, "{compression:?} didn't round trip");
+            assert_eq!(reader.get_ref().position() as usize, reader.get_ref().get_ref().len());
+        }
+    }
+
+    #[test]
+    fn dropping_the_writer_without_finishing_it_fails_decryption() {
+        // Only the blocks that were followed by more data were written, so the final block is missing
+        let mut writer = EncryptingWriter::new(Vec::new(), PASSWORD.to_string(), test_options(Cipher::ChaCha20Poly1305, Compression::None)).unwrap();
+        writer.write_all(&test_data(3 * BLOCK_SIZE + 100)).unwrap();
+        let encrypted = writer.get_ref().clone();
+        drop(writer);
+        assert_eq!(encrypted.len(), block_range(3).start);
+        assert_eq!(corrupted_message(decrypt(&encrypted, PASSWORD)), "The encrypted file is truncated (the final block is missing)");
+
+        // Nothing but the header was written
+        let mut writer = EncryptingWriter::new(Vec::new(), PASSWORD.to_string(), test_options(Cipher::ChaCha20Poly1305, Compression::None)).unwrap();
+        writer.write_all(b"Hello, world!").unwrap();
+        let encrypted = writer.get_ref().clone();
+        drop(writer);
+        assert_eq!(corrupted_message(decrypt(&encrypted, PASSWORD)), "The encrypted file is truncated (it doesn't contain any blocks)");
+    }
+
+    #[test]
+    fn a_modified_block_is_an_io_error_that_converts_back() {
+        let mut encrypted = encrypt(&test_data(3 * BLOCK_SIZE + 100), test_options(Cipher::ChaCha20Poly1305, Compression::None));
+        encrypted[block_range(1).start] ^= 0x01;
+
+        let mut reader = DecryptingReader::new(Cursor::new(encrypted), PASSWORD.to_string()).unwrap();
+        let mut plaintext = Vec::new();
+        let io_error = reader.read_to_end(&mut plaintext).unwrap_err();
+        assert_eq!(io_error.kind(), ErrorKind::InvalidData);
+
+        // Block 0 was authenticated and returned before block 1 failed
+        assert_eq!(plaintext, test_data(BLOCK_SIZE));
+        match Error::io("Failed to decrypt", io_error) {
+            Error::Corrupted(message) => assert_eq!(message, "Block 1 was modified or corrupted"),
+            error => panic!("expected Error::Corrupted, got {error:?}")
+        };
+    }
+
+    #[test]
+    fn every_segment_has_its_own_key() {
+        let mut cryptor = Cryptor::new(Cipher::XChaCha20Poly1305, [0x42; 32], None).unwrap();
+        let block = test_data(100);
+
+        // The block index within the segment is the same, so only the key of the segment tells these blocks apart
+        let encrypted_block = cryptor.encrypt_bytes(&block, SEGMENT_SIZE, false).unwrap();
+        assert!(cryptor.decrypt_bytes(&encrypted_block, 0, false).is_err());
+        assert_eq!(cryptor.decrypt_bytes(&encrypted_block, SEGMENT_SIZE, false).unwrap(), block);
+        assert_ne!(cryptor.encrypt_bytes(&block, 0, false).unwrap(), encrypted_block);
+    }
+}
@@ -0,0 +1,131 @@
+/*
+Copyright (C) 2023 Elijah Fry
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see https://www.gnu.org/licenses/gpl-3.0.html.
+*/
+
+
+use std::{fmt, io::ErrorKind, path::PathBuf};
+
+
+/// A `Result` that uses arch-crypt's `Error`
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// The errors returned by arch-crypt
+/// 
+/// # Notes
+/// More errors may be added later, so a `match` on an `Error` needs a `_` arm
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// The password, key file or private key is incorrect, or none of the key slots of the file can be unlocked with the credentials that were given. A key slot whose salt or KDF parameters were modified also ends up here
+    WrongPassword,
+    /// The encrypted data was modified, damaged, truncated or reordered. The message describes what we found
+    Corrupted(String),
+    /// Reading or writing failed. The message describes what we were doing at the time
+    Io {
+        context: String,
+        source: std::io::Error
+    },
+    /// The input isn't in a format we can read (I.E. it isn't an arch-crypt file, or it was written by a newer version of arch-crypt)
+    UnsupportedFormat(String),
+    /// The output file or directory already exists, and we refuse to overwrite it
+    OutputExists(PathBuf),
+    /// A cryptographic operation failed for a reason that isn't the data's fault (I.E. invalid Argon2id parameters, or the file is too large)
+    Crypto(String)
+}
+
+impl Error {
+    /// Returns an `Error::Io` with a description of what we were doing when the I/O error happened
+    ///
+    /// # Notes
+    /// Errors from our own `Read` and `Write` implementations are passed through I/O errors, so they are unwrapped here instead of being reported as I/O errors.
+    /// If the output already exists, `Error::OutputExists` is returned instead
+    pub fn io<T: Into<String>>(context: T, source: std::io::Error) -> Self {
+        let source = match source.downcast::<Self>() {
+            Ok(error) => {
+                return error;
+            },
+            Err(source) => source
+        };
+
+        // Other libraries (I.E. tar) may wrap our errors in their own, so look for them further down the chain too
+        // Our error can't be moved out of the chain, so the same kind of error is created again with the outer error as its source
+        let mut cause = std::error::Error::source(&source);
+        while let Some(error) = cause {
+            // Our errors are usually hidden inside another I/O error, so look inside those too
+            let ours = match error.downcast_ref::<std::io::Error>() {
+                Some(io_error) => io_error.get_ref().and_then(|inner| inner.downcast_ref::<Self>()),
+                None => error.downcast_ref::<Self>()
+            };
+            match ours {
+                Some(Self::WrongPassword) => return Self::WrongPassword,
+                Some(Self::Corrupted(message)) => return Self::Corrupted(message.clone()),
+                Some(Self::UnsupportedFormat(message)) => return Self::UnsupportedFormat(message.clone()),
+                Some(Self::OutputExists(path)) => return Self::OutputExists(path.clone()),
+                Some(Self::Crypto(message)) => return Self::Crypto(message.clone()),
+                Some(Self::Io { .. }) | None => {}
+            }
+            cause = error.source();
+        }
+
+        Self::Io {
+            context: context.into(),
+            source
+        }
+    }
+
+    /// Returns an `Error` for a failure to create an output file or directory, turning 'already exists' into `Error::OutputExists`
+    pub fn create<T: Into<PathBuf>>(path: T, source: std::io::Error) -> Self {
+        let path = path.into();
+        match source.kind() {
+            ErrorKind::AlreadyExists => Self::OutputExists(path),
+            _ => Self::io(format!("Couldn't create '{}'", path.display()), source)
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::WrongPassword => write!(f, "Incorrect password or key"),
+            Self::Corrupted(message) => write!(f, "{message}"),
+            Self::Io { context, .. } => write!(f, "{context}"),
+            Self::UnsupportedFormat(message) => write!(f, "{message}"),
+            Self::OutputExists(path) => write!(f, "'{}' already exists", path.display()),
+            Self::Crypto(message) => write!(f, "{message}")
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io { source, .. } => Some(source),
+            _ => None
+        }
+    }
+}
+
+// Lets our errors pass through `Read` and `Write` implementations. They're unwrapped again by `Error::io`
+impl From<Error> for std::io::Error {
+    fn from(error: Error) -> Self {
+        let kind = match &error {
+            Error::Io { source, .. } => source.kind(),
+            Error::OutputExists(_) => ErrorKind::AlreadyExists,
+            _ => ErrorKind::InvalidData
+        };
+        std::io::Error::new(kind, error)
+    }
+}
@@ -16,87 +16,37 @@ along with this program.  If not, see https://www.gnu.org/licenses/gpl-3.0.html.
 */
 
 
-use std::fs::{File, OpenOptions, Metadata};
-use std::io::{Read, Write};
-use log::error;
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+use crate::modules::error::{Error, Result};
 
 
-pub struct FileReaderWriter {
-    input_file: File,
-    pub input_file_metadata: Metadata,
-    output_file: File
-}
-
-impl FileReaderWriter {
-    pub fn new(input_file_name: &str, output_file_name: &str) -> Result<Self, ()> {
-        let input_file = OpenOptions::new()
-        .write(false)
-        .read(true)
-        .open(input_file_name);
-
-        let output_file = OpenOptions::new()
-        .write(true)
-        .read(false)
-        .create(true)
-        .open(output_file_name);
-
-        let input_file = match input_file {
-            Ok(input_file) => input_file,
-            Err(error) => {
-                error!("Couldn't open the input file '{input_file_name}':\n {error}");
-                return Err(());
-            }
-        };
-
-        let output_file = match output_file {
-            Ok(output_file) => output_file,
-            Err(error) => {
-                error!("Couldn't open the output file '{output_file_name}':\n {error}");
-                return Err(());
-            }
-        };
-
-        let input_file_metadata = match input_file.metadata() {
-            Ok(input_file_metadata) => input_file_metadata,
-            Err(error) => {
-                error!("Couldn't get metadata from input file:\n {error}");
-                return Err(());
-            }
-        }; 
+/// Opens a file for reading
+pub(crate) fn open_input_file<T: AsRef<Path>>(input_file_name: T) -> Result<File> {
+    let input_file = OpenOptions::new()
+    .write(false)
+    .read(true)
+    .open(&input_file_name);
 
-        // This is currently unneeded, but consider implementing a BufReader in the future if performance becomes a big concern
-        // let mut bufrdr = std::io::BufReader::new(file);
-
-        Ok(Self {
-            input_file,
-            input_file_metadata,
-            output_file
-        })
-
-    }
-
-    pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize, ()> {
-        match self.input_file.read(buffer) {
-            Ok(num_bytes_read) => {
-                Ok(num_bytes_read)
-            },
-            Err(error) => {
-                error!("Failed to read bytes from input file:\n {error}");
-                Err(())
-            }
-        }
+    match input_file {
+        Ok(input_file) => Ok(input_file),
+        Err(error) => Err(Error::io(format!("Couldn't open the input file '{}'", input_file_name.as_ref().display()), error))
     }
+}
 
-    pub fn write(&mut self, buffer: &[u8]) -> Result<usize, ()> {
-        match self.output_file.write(buffer) {
-            Ok(num_bytes_written) => {
-                Ok(num_bytes_written)
-            },
-            Err(error) => {
-                error!("Failed to write bytes to output file:\n {error}");
-                Err(())
-            }
-        }
+/// Creates a new file for writing
+/// 
+/// # Notes
+/// Existing files are never overwritten, `Error::OutputExists` is returned instead
+pub(crate) fn create_output_file<T: AsRef<Path>>(output_file_name: T) -> Result<File> {
+    let output_file = OpenOptions::new()
+    .write(true)
+    .read(false)
+    .create_new(true)
+    .open(&output_file_name);
+
+    match output_file {
+        Ok(output_file) => Ok(output_file),
+        Err(error) => Err(Error::create(output_file_name.as_ref(), error))
     }
 }
-
@@ -0,0 +1,662 @@
+/*
+Copyright (C) 2023 Elijah Fry
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see https://www.gnu.org/licenses/gpl-3.0.html.
+*/
+
+
+use crate::modules::{encryption::MAX_NONCE_PREFIX_SIZE, error::{Error, Result}, shamir::{MIN_THRESHOLD, SPLIT_ID_SIZE}};
+
+
+// Every file encrypted by arch-crypt starts with these magic bytes, followed by the FORMAT_VERSION byte
+// Version 1 is the format of arch-crypt 1.0, which didn't have a header at all (see `decrypt_legacy_file`)
+pub(crate) const MAGIC: [u8; 8] = *b"ARCHCRPT";
+pub(crate) const FORMAT_VERSION: u8 = 2;
+
+// The size of the start of the header, which never changes once the file is encrypted. It's authenticated along with every block
+// magic (8) + version (1) + cipher (1) + compression (1) + block size (4) + nonce prefix (19)
+pub(crate) const HEADER_CORE_SIZE: usize = 34;
+
+// The size of the part of the header that tells us how large the rest of it is
+// core + number of key slots (1)
+pub(crate) const HEADER_PREFIX_SIZE: usize = HEADER_CORE_SIZE + 1;
+
+// Every key slot takes up the same number of bytes in the header: the type of its recipient (1, or 0 if it's empty), followed by its fields (zero padded)
+// This means that key slots can be filled or emptied without changing the size of the header
+pub(crate) const SLOT_SIZE: usize = 96;
+
+/// The number of key slots in the header of a new file, unless more are needed for the recipients
+pub(crate) const DEFAULT_KEY_SLOTS: u8 = 8;
+
+// The size of the keyed BLAKE2b hash at the end of the header, which authenticates the whole header with the file key
+pub(crate) const HEADER_MAC_SIZE: usize = 32;
+
+// The size of the random file key, once it has been encrypted for a recipient (the key and a 16-byte Poly1305 tag)
+pub(crate) const WRAPPED_KEY_SIZE: usize = 48;
+
+// The range of block sizes that we're willing to decrypt
+// This stops a damaged header from making us allocate a huge buffer
+const MIN_BLOCK_SIZE: u32 = 1024;
+const MAX_BLOCK_SIZE: u32 = 16 * 1024 * 1024;
+
+
+/// The AEAD cipher used to encrypt the blocks of a file
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Cipher {
+    #[default]
+    ChaCha20Poly1305 = 1,
+    XChaCha20Poly1305 = 2,
+    Aes256GcmSiv = 3
+}
+
+impl Cipher {
+    /// Returns the `Cipher` with the given id, if we know about it
+    pub(crate) fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(Self::ChaCha20Poly1305),
+            2 => Some(Self::XChaCha20Poly1305),
+            3 => Some(Self::Aes256GcmSiv),
+            _ => None
+        }
+    }
+
+    /// Returns the `Cipher` with the given name, as used on the command line
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "chacha20-poly1305" => Some(Self::ChaCha20Poly1305),
+            "xchacha20-poly1305" => Some(Self::XChaCha20Poly1305),
+            "aes-256-gcm-siv" => Some(Self::Aes256GcmSiv),
+            _ => None
+        }
+    }
+
+    /// Returns the size of the random nonce prefix used by the STREAM construction
+    /// 
+    /// # Notes
+    /// The last 5 bytes of every nonce hold a 32-bit block counter and a 'last block' flag, and the rest is the nonce prefix
+    pub(crate) fn nonce_prefix_size(&self) -> usize {
+        match self {
+            Self::ChaCha20Poly1305 => 7,
+            Self::XChaCha20Poly1305 => 19,
+            Self::Aes256GcmSiv => 7
+        }
+    }
+
+    /// Returns true if the cipher uses a new key for every segment of a file
+    /// 
+    /// # Notes
+    /// This lifts the limit of 2^32 blocks per file, so files of any practical size can be encrypted
+    pub(crate) fn rekeys_segments(&self) -> bool {
+        matches!(self, Self::XChaCha20Poly1305)
+    }
+}
+
+/// Someone who can decrypt a file. Every recipient holds its own encrypted copy of the random file key, in a key slot of the header
+/// 
+/// # Notes
+/// Any one of the recipients is enough to decrypt the file
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Recipient {
+    /// The file key is encrypted with a key derived from a password with Argon2id
+    Password {
+        kdf_params: KdfParams,
+        salt: [u8; 32],
+        wrapped_key: [u8; WRAPPED_KEY_SIZE]
+    },
+    /// The file key is encrypted with a key derived from a key file and the salt with keyed BLAKE2b
+    KeyFile {
+        salt: [u8; 32],
+        wrapped_key: [u8; WRAPPED_KEY_SIZE]
+    },
+    /// The file key is encrypted with a key derived from a password with Argon2id, combined with a key file using keyed BLAKE2b. Both are needed
+    PasswordAndKeyFile {
+        kdf_params: KdfParams,
+        salt: [u8; 32],
+        wrapped_key: [u8; WRAPPED_KEY_SIZE]
+    },
+    /// The file key is encrypted with a key agreed between a random (ephemeral) X25519 key and the recipient's X25519 public key
+    X25519 {
+        ephemeral_public_key: [u8; 32],
+        wrapped_key: [u8; WRAPPED_KEY_SIZE]
+    },
+    /// The file key is encrypted with a key derived from a random recovery key and the salt with keyed BLAKE2b
+    RecoveryKey {
+        salt: [u8; 32],
+        wrapped_key: [u8; WRAPPED_KEY_SIZE]
+    },
+    /// The file key is encrypted with a random key that was split into `num_shares` Shamir shares, any `threshold` of which can recover it
+    Shares {
+        split_id: [u8; SPLIT_ID_SIZE],
+        threshold: u8,
+        num_shares: u8,
+        wrapped_key: [u8; WRAPPED_KEY_SIZE]
+    }
+}
+
+impl Recipient {
+    /// Returns a short description of what's needed to decrypt the file key of the recipient, as shown to the user
+    pub fn description(&self) -> &'static str {
+        match self {
+            Self::Password { .. } => "a password",
+            Self::KeyFile { .. } => "a key file",
+            Self::PasswordAndKeyFile { .. } => "a password and a key file",
+            Self::X25519 { .. } => "an X25519 private key",
+            Self::RecoveryKey { .. } => "a recovery key",
+            Self::Shares { .. } => "a threshold set of shares"
+        }
+    }
+
+    /// Serializes the recipient into the bytes that are stored in the header
+    pub(crate) fn to_bytes(&self) -> [u8; SLOT_SIZE] {
+        let mut bytes = [0u8; SLOT_SIZE];
+
+        match self {
+            Self::Password { kdf_params, salt, wrapped_key } | Self::PasswordAndKeyFile { kdf_params, salt, wrapped_key } => {
+                bytes[0] = if matches!(self, Self::Password { .. }) { 1 } else { 3 };
+                bytes[1..14].copy_from_slice(&kdf_params.to_bytes());
+                bytes[14..46].copy_from_slice(salt);
+                bytes[46..94].copy_from_slice(wrapped_key);
+            },
+            Self::KeyFile { salt, wrapped_key } => {
+                bytes[0] = 2;
+                bytes[1..33].copy_from_slice(salt);
+                bytes[33..81].copy_from_slice(wrapped_key);
+            },
+            Self::X25519 { ephemeral_public_key, wrapped_key } => {
+                bytes[0] = 4;
+                bytes[1..33].copy_from_slice(ephemeral_public_key);
+                bytes[33..81].copy_from_slice(wrapped_key);
+            },
+            Self::RecoveryKey { salt, wrapped_key } => {
+                bytes[0] = 5;
+                bytes[1..33].copy_from_slice(salt);
+                bytes[33..81].copy_from_slice(wrapped_key);
+            },
+            Self::Shares { split_id, threshold, num_shares, wrapped_key } => {
+                bytes[0] = 6;
+                bytes[1..17].copy_from_slice(split_id);
+                bytes[17] = *threshold;
+                bytes[18] = *num_shares;
+                bytes[19..67].copy_from_slice(wrapped_key);
+            }
+        }
+
+        bytes
+    }
+
+    /// Parses a recipient from the bytes stored in the header
+    /// 
+    /// # Arguments
+    /// * `bytes` - Exactly SLOT_SIZE bytes
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        match bytes[0] {
+            1 | 3 => {
+                let kdf_params = match KdfParams::from_bytes(&bytes[1..14]) {
+                    Ok(resp) => resp,
+                    Err(error) => {
+                        return Err(error);
+                    }
+                };
+                let salt = read_array(&bytes[14..46]);
+                let wrapped_key = read_array(&bytes[46..94]);
+                if bytes[0] == 1 {
+                    Ok(Self::Password { kdf_params, salt, wrapped_key })
+                } else {
+                    Ok(Self::PasswordAndKeyFile { kdf_params, salt, wrapped_key })
+                }
+            },
+            2 => Ok(Self::KeyFile {
+                salt: read_array(&bytes[1..33]),
+                wrapped_key: read_array(&bytes[33..81])
+            }),
+            4 => Ok(Self::X25519 {
+                ephemeral_public_key: read_array(&bytes[1..33]),
+                wrapped_key: read_array(&bytes[33..81])
+            }),
+            5 => Ok(Self::RecoveryKey {
+                salt: read_array(&bytes[1..33]),
+                wrapped_key: read_array(&bytes[33..81])
+            }),
+            6 => {
+                // The key slots are read before the header MAC can be checked, so a threshold that no split could have is rejected here
+                let (threshold, num_shares) = (bytes[17], bytes[18]);
+                if threshold < MIN_THRESHOLD || threshold > num_shares {
+                    return Err(Error::Corrupted(format!("The header of the input file has a key slot for {threshold} of {num_shares} shares, it was probably modified")));
+                }
+                Ok(Self::Shares {
+                    split_id: read_array(&bytes[1..17]),
+                    threshold,
+                    num_shares,
+                    wrapped_key: read_array(&bytes[19..67])
+                })
+            },
+            id => Err(Error::UnsupportedFormat(format!("The input file has an unsupported type of recipient (id {id})")))
+        }
+    }
+}
+
+/// The algorithm used to compress the data before it's encrypted, or to compress a tarball
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Compression {
+    #[default]
+    None = 0,
+    Zstd = 1,
+    Snappy = 2,
+    Gzip = 3,
+    Xz = 4
+}
+
+impl Compression {
+    /// Returns the `Compression` with the given id, if we know about it
+    pub(crate) fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Self::None),
+            1 => Some(Self::Zstd),
+            2 => Some(Self::Snappy),
+            3 => Some(Self::Gzip),
+            4 => Some(Self::Xz),
+            _ => None
+        }
+    }
+
+    /// Returns the `Compression` with the given name, as used on the command line
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(Self::None),
+            "zstd" => Some(Self::Zstd),
+            "snappy" => Some(Self::Snappy),
+            "gzip" => Some(Self::Gzip),
+            "xz" => Some(Self::Xz),
+            _ => None
+        }
+    }
+}
+
+/// The largest Argon2id memory cost that's accepted, in KiB (4 GiB)
+pub const MAX_MEMORY_COST: u32 = 4 * 1024 * 1024;
+
+/// The largest number of Argon2id iterations that's accepted
+pub const MAX_TIME_COST: u32 = 64;
+
+/// The largest degree of Argon2id parallelism that's accepted
+pub const MAX_PARALLELISM: u32 = 255;
+
+// The id of the key derivation function that passwords are hashed with, stored in front of its parameters
+// Only Argon2id exists so far, but the id lets another KDF be added without a new format version
+const ARGON2ID_KDF_ID: u8 = 1;
+
+/// The cost parameters given to the key derivation function
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KdfParams {
+    /// Memory cost in KiB
+    pub memory_cost: u32,
+    /// Number of iterations
+    pub time_cost: u32,
+    /// Degree of parallelism
+    pub parallelism: u32
+}
+
+impl KdfParams {
+    /// Serializes the id of the KDF (Argon2id) and its parameters into the bytes that are stored in the header
+    pub(crate) fn to_bytes(self) -> [u8; 13] {
+        let mut bytes = [0u8; 13];
+        bytes[0] = ARGON2ID_KDF_ID;
+        bytes[1..5].copy_from_slice(&self.memory_cost.to_le_bytes());
+        bytes[5..9].copy_from_slice(&self.time_cost.to_le_bytes());
+        bytes[9..13].copy_from_slice(&self.parallelism.to_le_bytes());
+        bytes
+    }
+
+    /// Parses the id of the KDF and its parameters from the 13 bytes stored in the header
+    /// 
+    /// # Notes
+    /// The key slots are read before the header MAC can be checked, so parameters above the limits (see `is_within_limits`) are rejected here.
+    /// Otherwise a modified header could make us allocate terabytes of memory, or hash the password for hours
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes[0] != ARGON2ID_KDF_ID {
+            return Err(Error::UnsupportedFormat(format!("The input file uses an unsupported key derivation function (id {})", bytes[0])));
+        }
+        let kdf_params = Self {
+            memory_cost: read_u32(&bytes[1..5]),
+            time_cost: read_u32(&bytes[5..9]),
+            parallelism: read_u32(&bytes[9..13])
+        };
+        if !kdf_params.is_within_limits() {
+            return Err(Error::Corrupted(format!("The header of the input file has Argon2id parameters above the limits arch-crypt accepts ({kdf_params:?}), it was probably modified")));
+        }
+        Ok(kdf_params)
+    }
+
+    /// Returns true if none of the parameters are above `MAX_MEMORY_COST`, `MAX_TIME_COST` and `MAX_PARALLELISM`
+    pub fn is_within_limits(&self) -> bool {
+        self.memory_cost <= MAX_MEMORY_COST && self.time_cost <= MAX_TIME_COST && self.parallelism <= MAX_PARALLELISM
+    }
+}
+
+// The defaults are spelled out instead of using argon2::Params::default(), which could change when the argon2 crate is updated
+// These are the defaults of argon2 0.5, which arch-crypt 1.0 used for every file
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self {
+            memory_cost: 19456,
+            time_cost: 2,
+            parallelism: 1
+        }
+    }
+}
+
+/// The header written to the start of every encrypted file
+///
+/// # Notes
+/// All integers are stored in little-endian byte order.
+/// The core of the header (everything before the key slots) is used as associated data for every block, so changing it will make decryption fail.
+/// The key slots can be changed without touching the blocks, but the header MAC (keyed with the file key) must be updated to match
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Header {
+    pub cipher: Cipher,
+    pub compression: Compression,
+    pub block_size: u32,
+    /// Ciphers with a shorter nonce only use the start of this, and the rest is zeroed
+    pub nonce: [u8; MAX_NONCE_PREFIX_SIZE],
+    /// Everyone who can decrypt the file. Empty key slots are `None`
+    pub slots: Vec<Option<Recipient>>,
+    /// Used to check that the header wasn't modified, once the file key has been decrypted
+    pub mac: [u8; HEADER_MAC_SIZE]
+}
+
+impl Header {
+    /// Returns the size of a header with the given number of key slots
+    pub(crate) fn size(num_slots: usize) -> usize {
+        HEADER_PREFIX_SIZE + num_slots * SLOT_SIZE + HEADER_MAC_SIZE
+    }
+
+    /// Serializes the header into the bytes that are written to the start of an encrypted file
+    /// 
+    /// # Notes
+    /// There must be between 1 and 255 key slots, and at least one of them must be in use
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(Self::size(self.slots.len()));
+
+        bytes.extend_from_slice(&MAGIC);
+        bytes.push(FORMAT_VERSION);
+        bytes.push(self.cipher as u8);
+        bytes.push(self.compression as u8);
+        bytes.extend_from_slice(&self.block_size.to_le_bytes());
+        bytes.extend_from_slice(&self.nonce);
+        bytes.push(self.slots.len() as u8);
+        for slot in &self.slots {
+            match slot {
+                Some(recipient) => bytes.extend_from_slice(&recipient.to_bytes()),
+                None => bytes.extend_from_slice(&[0u8; SLOT_SIZE])
+            }
+        }
+        bytes.extend_from_slice(&self.mac);
+
+        bytes
+    }
+
+    /// Validates the start of the header, and returns the size of the whole header
+    ///
+    /// # Arguments
+    /// * `bytes` - The bytes read from the start of the file. This may be shorter than HEADER_PREFIX_SIZE if the file is small
+    pub(crate) fn size_from_prefix(bytes: &[u8]) -> Result<usize> {
+        // Make sure that this is actually a file encrypted by arch-crypt
+        if bytes.len() < MAGIC.len() + 1 || bytes[0..8] != MAGIC {
+            return Err(Error::UnsupportedFormat("The input file is not an arch-crypt file (if it was encrypted by arch-crypt 1.0, try decrypting it with --legacy)".to_string()));
+        }
+
+        // Make sure that we know how to read this version of the format
+        if bytes[8] != FORMAT_VERSION {
+            return Err(Error::UnsupportedFormat(format!("Unsupported file format version {} (this version of arch-crypt supports version {FORMAT_VERSION})", bytes[8])));
+        }
+
+        // Make sure that the prefix is all there
+        if bytes.len() < HEADER_PREFIX_SIZE {
+            return Err(Error::Corrupted("The header of the input file is truncated".to_string()));
+        }
+
+        // Every file has at least one key slot, otherwise it could never be decrypted
+        let num_slots = bytes[HEADER_CORE_SIZE] as usize;
+        if num_slots == 0 {
+            return Err(Error::Corrupted("The header of the input file doesn't have any key slots".to_string()));
+        }
+
+        Ok(Self::size(num_slots))
+    }
+
+    /// Parses and validates the header at the start of an encrypted file
+    ///
+    /// # Arguments
+    /// * `bytes` - The bytes read from the start of the file. This may be shorter than the header if the file is small
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        // Validate the start of the header, and make sure that the rest of it is all there
+        let header_size = match Self::size_from_prefix(bytes) {
+            Ok(resp) => resp,
+            Err(error) => {
+                return Err(error);
+            }
+        };
+        if bytes.len() < header_size {
+            return Err(Error::Corrupted("The header of the input file is truncated".to_string()));
+        }
+
+        // Parse the cipher and compression algorithm
+        let cipher = match Cipher::from_id(bytes[9]) {
+            Some(resp) => resp,
+            None => {
+                return Err(Error::UnsupportedFormat(format!("The input file uses an unsupported cipher (id {})", bytes[9])));
+            }
+        };
+        let compression = match Compression::from_id(bytes[10]) {
+            Some(resp) => resp,
+            None => {
+                return Err(Error::UnsupportedFormat(format!("The input file uses an unsupported compression algorithm (id {})", bytes[10])));
+            }
+        };
+
+        // Parse and validate the block size
+        let block_size = read_u32(&bytes[11..15]);
+        if !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size) {
+            return Err(Error::UnsupportedFormat(format!("The input file uses an unsupported block size ({block_size} bytes)")));
+        }
+
+        // Parse the nonce prefix
+        let nonce = read_array(&bytes[15..HEADER_CORE_SIZE]);
+
+        // Parse the key slots, and make sure that at least one of them is in use
+        let mut slots = Vec::new();
+        for slot_bytes in bytes[HEADER_PREFIX_SIZE..header_size - HEADER_MAC_SIZE].chunks_exact(SLOT_SIZE) {
+            if slot_bytes[0] == 0 {
+                slots.push(None);
+                continue;
+            }
+            match Recipient::from_bytes(slot_bytes) {
+                Ok(resp) => slots.push(Some(resp)),
+                Err(error) => {
+                    return Err(error);
+                }
+            };
+        }
+        if slots.iter().all(Option::is_none) {
+            return Err(Error::Corrupted("All of the key slots of the input file are empty".to_string()));
+        }
+
+        // Parse the header MAC
+        let mac = read_array(&bytes[header_size - HEADER_MAC_SIZE..header_size]);
+
+        Ok(Self {
+            cipher,
+            compression,
+            block_size,
+            nonce,
+            slots,
+            mac
+        })
+    }
+}
+
+// Reads a little-endian u32 from a 4-byte slice
+fn read_u32(bytes: &[u8]) -> u32 {
+    let mut buffer = [0u8; 4];
+    buffer.copy_from_slice(bytes);
+    u32::from_le_bytes(buffer)
+}
+
+// Copies a slice into a fixed-size array. The slice must be exactly N bytes long
+fn read_array<const N: usize>(bytes: &[u8]) -> [u8; N] {
+    let mut buffer = [0u8; N];
+    buffer.copy_from_slice(bytes);
+    buffer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KDF_PARAMS: KdfParams = KdfParams { memory_cost: 19456, time_cost: 2, parallelism: 1 };
+
+    // A header with one key slot of every type, and an empty one in the middle
+    fn test_header() -> Header {
+        Header {
+            cipher: Cipher::XChaCha20Poly1305,
+            compression: Compression::Zstd,
+            block_size: 32768,
+            nonce: [0x11; MAX_NONCE_PREFIX_SIZE],
+            slots: vec![
+                Some(Recipient::Password { kdf_params: KDF_PARAMS, salt: [0x21; 32], wrapped_key: [0x31; WRAPPED_KEY_SIZE] }),
+                Some(Recipient::KeyFile { salt: [0x22; 32], wrapped_key: [0x32; WRAPPED_KEY_SIZE] }),
+                None,
+                Some(Recipient::PasswordAndKeyFile { kdf_params: KDF_PARAMS, salt: [0x23; 32], wrapped_key: [0x33; WRAPPED_KEY_SIZE] }),
+                Some(Recipient::X25519 { ephemeral_public_key: [0x24; 32], wrapped_key: [0x34; WRAPPED_KEY_SIZE] }),
+                Some(Recipient::RecoveryKey { salt: [0x25; 32], wrapped_key: [0x35; WRAPPED_KEY_SIZE] }),
+                Some(Recipient::Shares { split_id: [0x26; SPLIT_ID_SIZE], threshold: 3, num_shares: 5, wrapped_key: [0x36; WRAPPED_KEY_SIZE] })
+            ],
+            mac: [0x41; HEADER_MAC_SIZE]
+        }
+    }
+
+    // Returns the message of an Error::UnsupportedFormat or an Error::Corrupted, and fails the test for anything else
+    fn error_message(result: Result<Header>) -> String {
+        match result {
+            Err(Error::UnsupportedFormat(message)) | Err(Error::Corrupted(message)) => message,
+            Err(error) => panic!("expected Error::UnsupportedFormat or Error::Corrupted, got {error:?}"),
+            Ok(_resp) => panic!("expected an error, but the header was parsed")
+        }
+    }
+
+    #[test]
+    fn every_type_of_key_slot_round_trips() {
+        let header = test_header();
+        let bytes = header.to_bytes();
+        assert_eq!(bytes.len(), Header::size(header.slots.len()));
+        assert_eq!(Header::size_from_prefix(&bytes[..HEADER_PREFIX_SIZE]).unwrap(), bytes.len());
+        assert_eq!(Header::from_bytes(&bytes).unwrap(), header);
+    }
+
+    #[test]
+    fn the_default_header_is_835_bytes() {
+        assert_eq!(Header::size(DEFAULT_KEY_SLOTS as usize), 835);
+    }
+
+    #[test]
+    fn the_core_is_laid_out_as_documented() {
+        let bytes = test_header().to_bytes();
+        assert_eq!(bytes[0..8], MAGIC);
+        assert_eq!(bytes[8], FORMAT_VERSION);
+        assert_eq!(bytes[9], Cipher::XChaCha20Poly1305 as u8);
+        assert_eq!(bytes[10], Compression::Zstd as u8);
+        assert_eq!(bytes[11..15], 32768u32.to_le_bytes());
+        assert_eq!(bytes[15..HEADER_CORE_SIZE], [0x11; MAX_NONCE_PREFIX_SIZE]);
+        assert_eq!(bytes[HEADER_CORE_SIZE], 7);
+    }
+
+    #[test]
+    fn other_files_and_versions_are_rejected() {
+        let mut bytes = test_header().to_bytes();
+        assert!(matches!(Header::from_bytes(b"not an arch-crypt file"), Err(Error::UnsupportedFormat(_))));
+        assert!(matches!(Header::from_bytes(b""), Err(Error::UnsupportedFormat(_))));
+
+        bytes[8] = 1;
+        assert_eq!(error_message(Header::from_bytes(&bytes)), "Unsupported file format version 1 (this version of arch-crypt supports version 2)");
+    }
+
+    #[test]
+    fn unknown_ids_are_unsupported() {
+        let bytes = test_header().to_bytes();
+
+        let mut modified = bytes.clone();
+        modified[9] = 0xff;
+        assert_eq!(error_message(Header::from_bytes(&modified)), "The input file uses an unsupported cipher (id 255)");
+
+        let mut modified = bytes.clone();
+        modified[10] = 0xff;
+        assert_eq!(error_message(Header::from_bytes(&modified)), "The input file uses an unsupported compression algorithm (id 255)");
+
+        // The KDF id is the second byte of a password key slot
+        let mut modified = bytes.clone();
+        modified[HEADER_PREFIX_SIZE + 1] = 2;
+        assert_eq!(error_message(Header::from_bytes(&modified)), "The input file uses an unsupported key derivation function (id 2)");
+
+        let mut modified = bytes.clone();
+        modified[HEADER_PREFIX_SIZE] = 0xff;
+        assert_eq!(error_message(Header::from_bytes(&modified)), "The input file has an unsupported type of recipient (id 255)");
+    }
+
+    #[test]
+    fn kdf_params_above_the_limits_are_rejected() {
+        let params = KdfParams { memory_cost: MAX_MEMORY_COST, time_cost: MAX_TIME_COST, parallelism: MAX_PARALLELISM };
+        assert_eq!(KdfParams::from_bytes(&params.to_bytes()).unwrap(), params);
+
+        for params in [
+            KdfParams { memory_cost: MAX_MEMORY_COST + 1, ..params },
+            KdfParams { time_cost: MAX_TIME_COST + 1, ..params },
+            KdfParams { parallelism: MAX_PARALLELISM + 1, ..params }
+        ] {
+            assert!(matches!(KdfParams::from_bytes(&params.to_bytes()), Err(Error::Corrupted(_))), "{params:?} wasn't rejected");
+        }
+    }
+
+    #[test]
+    fn malformed_headers_are_rejected() {
+        let bytes = test_header().to_bytes();
+
+        assert_eq!(error_message(Header::from_bytes(&bytes[..bytes.len() - 1])), "The header of the input file is truncated");
+        assert_eq!(error_message(Header::from_bytes(&bytes[..HEADER_CORE_SIZE])), "The header of the input file is truncated");
+
+        let mut modified = bytes.clone();
+        modified[HEADER_CORE_SIZE] = 0;
+        assert_eq!(error_message(Header::from_bytes(&modified)), "The header of the input file doesn't have any key slots");
+
+        let mut modified = bytes.clone();
+        modified[11..15].copy_from_slice(&(MAX_BLOCK_SIZE + 1).to_le_bytes());
+        assert!(matches!(Header::from_bytes(&modified), Err(Error::UnsupportedFormat(_))));
+
+        let header = Header { slots: vec![None, None], ..test_header() };
+        assert_eq!(error_message(Header::from_bytes(&header.to_bytes())), "All of the key slots of the input file are empty");
+    }
+
+    #[test]
+    fn impossible_thresholds_are_rejected() {
+        for (threshold, num_shares) in [(0, 5), (1, 5), (6, 5)] {
+            let header = Header {
+                slots: vec![Some(Recipient::Shares { split_id: [0x26; SPLIT_ID_SIZE], threshold, num_shares, wrapped_key: [0x36; WRAPPED_KEY_SIZE] })],
+                ..test_header()
+            };
+            assert!(matches!(Header::from_bytes(&header.to_bytes()), Err(Error::Corrupted(_))), "{threshold} of {num_shares} wasn't rejected");
+        }
+    }
+}
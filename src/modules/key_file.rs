@@ -0,0 +1,127 @@
+/*
+Copyright (C) 2023 Elijah Fry
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see https://www.gnu.org/licenses/gpl-3.0.html.
+*/
+
+
+use std::{fs::{File, OpenOptions}, io::{BufReader, Read, Write}, path::Path};
+use blake2::{Blake2b, digest::{consts::U32, Digest}};
+use chacha20poly1305::aead::{OsRng, rand_core::RngCore};
+use zeroize::Zeroize;
+use crate::modules::error::{Error, Result};
+
+
+/// The size of the key files created by `generate_key_file`. Key files of exactly this size are used as the key material directly
+pub const KEY_FILE_SIZE: usize = 32;
+
+// The message hashed before the contents of a key file that isn't KEY_FILE_SIZE bytes long
+const KEY_FILE_HASH_MESSAGE: &[u8] = b"arch-crypt key file";
+
+
+/// Reads a key file, and returns the key material in it
+/// 
+/// # Arguments
+/// * `key_file` - The location of the key file. This can be a file created by `generate_key_file`, or any other file (I.E. a photo)
+/// 
+/// # Notes
+/// A key file that's exactly 32 bytes long is used as-is. Any other file is hashed with BLAKE2b into 32 bytes of key material,
+/// so it must never change, otherwise the files encrypted with it can't be decrypted anymore
+pub fn read_key_file<T: AsRef<Path>>(key_file: T) -> Result<[u8; KEY_FILE_SIZE]> {
+    let file = match File::open(&key_file) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(Error::io(format!("Couldn't open the key file '{}'", key_file.as_ref().display()), error));
+        }
+    };
+
+    // Read the start of the key file, to work out if it's a raw key
+    let mut reader = BufReader::new(file);
+    let mut contents = Vec::with_capacity(KEY_FILE_SIZE + 1);
+    match reader.by_ref().take(KEY_FILE_SIZE as u64 + 1).read_to_end(&mut contents) {
+        Ok(_resp) => {},
+        Err(error) => {
+            contents.zeroize();
+            return Err(Error::io(format!("Failed to read the key file '{}'", key_file.as_ref().display()), error));
+        }
+    };
+    if contents.is_empty() {
+        return Err(Error::Crypto(format!("The key file '{}' is empty", key_file.as_ref().display())));
+    }
+
+    let mut key = [0u8; KEY_FILE_SIZE];
+    if contents.len() == KEY_FILE_SIZE {
+        // This is a raw key
+        key.copy_from_slice(&contents);
+    } else {
+        // Hash the whole file into the key material
+        let mut hasher = Blake2b::<U32>::new();
+        hasher.update(KEY_FILE_HASH_MESSAGE);
+        hasher.update(&contents);
+        match std::io::copy(&mut reader, &mut hasher) {
+            Ok(_resp) => {},
+            Err(error) => {
+                contents.zeroize();
+                return Err(Error::io(format!("Failed to read the key file '{}'", key_file.as_ref().display()), error));
+            }
+        };
+        key.copy_from_slice(&hasher.finalize());
+    }
+
+    // Zeroize the contents of the key file for security
+    contents.zeroize();
+
+    Ok(key)
+}
+
+/// Creates a new key file, containing 32 random bytes
+/// 
+/// # Notes
+/// The key file is never overwritten if it already exists, and (on Unix) only the owner can read it
+pub fn generate_key_file<T: AsRef<Path>>(key_file: T) -> Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = match options.open(&key_file) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(Error::create(key_file.as_ref(), error));
+        }
+    };
+
+    // Generate the key
+    let mut key = [0u8; KEY_FILE_SIZE];
+    OsRng.fill_bytes(&mut key);
+
+    // Write the key to the key file, and make sure that it reached the disk
+    let result = match file.write_all(&key) {
+        Ok(_resp) => file.sync_all(),
+        Err(error) => Err(error)
+    };
+
+    // Zeroize the key for security
+    key.zeroize();
+
+    match result {
+        Ok(_resp) => Ok(()),
+        Err(error) => {
+            let _ = std::fs::remove_file(&key_file);
+            Err(Error::io(format!("Failed to write the key file '{}'", key_file.as_ref().display()), error))
+        }
+    }
+}
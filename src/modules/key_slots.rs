@@ -0,0 +1,388 @@
+/*
+Copyright (C) 2023 Elijah Fry
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see https://www.gnu.org/licenses/gpl-3.0.html.
+*/
+
+use std::{fs::{File, OpenOptions}, io::{ErrorKind, Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}};
+use blake2::{Blake2b, digest::{consts::U32, Digest}};
+use log::info;
+use zeroize::Zeroize;
+use crate::modules::{encryption::{find_file_key, header_mac, read_header, verify_header_mac, wrap_for_everyone, Credentials}, error::{Error, Result}, file_handler::open_input_file, header::{Header, KdfParams, Recipient, HEADER_CORE_SIZE}};
+
+
+// The new header is written to a file with this extension (next to the encrypted file) before it's written over the old header
+// If we crash while the old header is being overwritten, the header can be finished from this journal
+const JOURNAL_EXTENSION: &str = ".header-journal";
+
+// The size of the BLAKE2b hash at the end of the journal, which tells us whether the journal itself was written completely
+const JOURNAL_HASH_SIZE: usize = 32;
+
+
+/// Reads the key slots from the header of an encrypted file
+/// 
+/// # Returns
+/// Every key slot in the header, in order. Empty key slots are `None`
+/// 
+/// # Notes
+/// No credentials are needed, so the key slots aren't authenticated. They're only checked when the file is decrypted (or its key slots are changed).
+/// The file is never written to. If a previous change to the key slots was interrupted, the key slots are the ones from before it (see `has_interrupted_rewrite`)
+pub fn list_key_slots<T: AsRef<Path>>(file: T) -> Result<Vec<Option<Recipient>>> {
+    let mut input = match open_input_file(&file) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(error);
+        }
+    };
+
+    let header_bytes = match read_header(&mut input) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(error);
+        }
+    };
+    match Header::from_bytes(&header_bytes) {
+        Ok(resp) => Ok(resp.slots),
+        Err(error) => Err(error)
+    }
+}
+
+/// Adds new credentials to empty key slots of an encrypted file, without re-encrypting the data
+/// 
+/// # Arguments
+/// * `file` - The location of the encrypted file. Only its header is rewritten
+/// * `credentials` - Credentials that can already decrypt the file
+/// * `new_credentials` - The password and/or key file (one key slot), and the public keys (one key slot each) that should also be able to decrypt the file
+/// * `kdf_params` - The Argon2id cost parameters used to hash the new password
+/// 
+/// # Returns
+/// The indexes of the key slots that were filled
+pub fn add_key_slots<T: AsRef<Path>, C: Into<Credentials>, N: Into<Credentials>>(file: T, credentials: C, new_credentials: N, kdf_params: KdfParams) -> Result<Vec<usize>> {
+    let new_credentials = new_credentials.into();
+
+    modify_key_slots(file, credentials.into(), |slots, file_key, _unlocked_slot| {
+        // Encrypt the file key for the new credentials, and put them in the empty key slots
+        match wrap_for_everyone(file_key, &new_credentials, kdf_params) {
+            Ok(resp) => fill_key_slots(slots, Vec::new(), resp),
+            Err(error) => Err(error)
+        }
+    })
+}
+
+/// Replaces the credentials that unlock an encrypted file with new ones (I.E. changes the password), without re-encrypting the data
+/// 
+/// # Arguments
+/// * `file` - The location of the encrypted file. Only its header is rewritten
+/// * `credentials` - The credentials to replace. They must be able to decrypt the file
+/// * `new_credentials` - The password and/or key file, and the public keys that replace them
+/// * `kdf_params` - The Argon2id cost parameters used to hash the new password
+/// 
+/// # Returns
+/// The indexes of the key slots that now hold the new credentials. The first one is the key slot of the old credentials
+/// 
+/// # Notes
+/// Only the key slot unlocked by `credentials` is replaced, the other key slots keep working.
+/// The header is replaced in a way that survives a crash (see `list_key_slots`), but anyone who saved a copy of the file with the old header can still decrypt that copy with the old credentials
+pub fn rekey<T: AsRef<Path>, C: Into<Credentials>, N: Into<Credentials>>(file: T, credentials: C, new_credentials: N, kdf_params: KdfParams) -> Result<Vec<usize>> {
+    let new_credentials = new_credentials.into();
+
+    modify_key_slots(file, credentials.into(), |slots, file_key, unlocked_slot| {
+        // Encrypt the file key for the new credentials, and put them in place of the old ones (and in empty key slots, if there are more of them)
+        match wrap_for_everyone(file_key, &new_credentials, kdf_params) {
+            Ok(resp) => {
+                slots[unlocked_slot] = None;
+                fill_key_slots(slots, vec![unlocked_slot], resp)
+            },
+            Err(error) => Err(error)
+        }
+    })
+}
+
+/// Empties a key slot of an encrypted file, so its credentials can't decrypt the file anymore, without re-encrypting the data
+/// 
+/// # Arguments
+/// * `file` - The location of the encrypted file. Only its header is rewritten
+/// * `credentials` - Credentials that can decrypt the file. They can belong to the key slot that's being removed
+/// * `slot` - The index of the key slot to empty (see `list_key_slots`)
+/// 
+/// # Notes
+/// The last key slot in use can't be removed, because the file could never be decrypted again.
+/// Anyone who saved a copy of the file (or its header) before the key slot was removed can still decrypt that copy with the old credentials
+pub fn remove_key_slot<T: AsRef<Path>, C: Into<Credentials>>(file: T, credentials: C, slot: usize) -> Result<()> {
+    modify_key_slots(file, credentials.into(), |slots, _file_key, _unlocked_slot| {
+        match slots.get(slot) {
+            Some(Some(_recipient)) => {},
+            Some(None) => {
+                return Err(Error::Crypto(format!("Key slot {slot} is already empty")));
+            },
+            None => {
+                return Err(Error::Crypto(format!("Key slot {slot} doesn't exist (the file has {} key slots)", slots.len())));
+            }
+        };
+        if slots.iter().filter(|slot| slot.is_some()).count() == 1 {
+            return Err(Error::Crypto(format!("Key slot {slot} is the only key slot in use, so removing it would make the file impossible to decrypt")));
+        }
+
+        slots[slot] = None;
+        Ok(())
+    })
+}
+
+// Puts the recipients in the preferred key slots, then in the other empty key slots in order, and returns the indexes of the key slots that were filled
+fn fill_key_slots(slots: &mut [Option<Recipient>], preferred_slots: Vec<usize>, recipients: Vec<Recipient>) -> Result<Vec<usize>> {
+    // Make sure that there's room for all of them, because the header can't grow without rewriting the whole file
+    let mut empty_slots = preferred_slots;
+    for (index, slot) in slots.iter().enumerate() {
+        if slot.is_none() && !empty_slots.contains(&index) {
+            empty_slots.push(index);
+        }
+    }
+    if empty_slots.len() < recipients.len() {
+        return Err(Error::Crypto(format!("The file doesn't have enough empty key slots ({} empty, {} needed). The number of key slots can only be chosen when the file is encrypted", empty_slots.len(), recipients.len())));
+    }
+
+    let mut filled_slots = Vec::with_capacity(recipients.len());
+    for (index, recipient) in empty_slots.into_iter().zip(recipients) {
+        slots[index] = Some(recipient);
+        filled_slots.push(index);
+    }
+    Ok(filled_slots)
+}
+
+// Decrypts the file key with the credentials, lets `modify` change the key slots, then writes the new header over the old one
+// `modify` is given the key slots, the file key, and the index of the key slot that the credentials unlocked
+fn modify_key_slots<T, F, R>(file: T, credentials: Credentials, modify: F) -> Result<R>
+where
+    T: AsRef<Path>,
+    F: FnOnce(&mut Vec<Option<Recipient>>, &[u8; 32], usize) -> Result<R>
+{
+    // Finish any change to the key slots that was interrupted, so we start from the header that was meant to be there
+    match finish_interrupted_rewrite(&file) {
+        Ok(_resp) => {},
+        Err(error) => {
+            return Err(error);
+        }
+    };
+
+    let mut file_handle = match OpenOptions::new().read(true).write(true).open(&file) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(Error::io(format!("Couldn't open the encrypted file '{}'", file.as_ref().display()), error));
+        }
+    };
+
+    // Read the header, and make sure that the credentials are correct and the header wasn't modified
+    let header_bytes = match read_header(&mut file_handle) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(error);
+        }
+    };
+    let mut header = match Header::from_bytes(&header_bytes) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(error);
+        }
+    };
+    let (unlocked_slot, mut file_key) = match find_file_key(&header, &credentials) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(error);
+        }
+    };
+    let result = match verify_header_mac(&file_key, &header_bytes) {
+        Ok(_resp) => modify(&mut header.slots, &file_key, unlocked_slot),
+        Err(error) => Err(error)
+    };
+    let result = match result {
+        Ok(resp) => {
+            // Authenticate the new key slots
+            match header_mac(&file_key, &header.to_bytes()) {
+                Ok(mac) => {
+                    header.mac = mac;
+                    Ok(resp)
+                },
+                Err(error) => Err(error)
+            }
+        },
+        Err(error) => Err(error)
+    };
+
+    // Zeroize the file_key for security
+    file_key.zeroize();
+
+    let result = match result {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(error);
+        }
+    };
+
+    // The number of key slots never changes, so the new header is exactly the same size as the old one
+    match replace_header(file.as_ref(), &mut file_handle, &header.to_bytes()) {
+        Ok(_resp) => Ok(result),
+        Err(error) => Err(error)
+    }
+}
+
+// Writes the new header over the old one, without losing the file if we crash part way through
+// The new header is written to the journal first, so an interrupted write can be finished by finish_interrupted_rewrite
+fn replace_header(file: &Path, file_handle: &mut File, header_bytes: &[u8]) -> Result<()> {
+    let journal = journal_path(file);
+
+    // Write the new header and its hash to the journal, and make sure that it reached the disk before we touch the file
+    let mut journal_bytes = header_bytes.to_vec();
+    journal_bytes.extend_from_slice(&Blake2b::<U32>::digest(header_bytes));
+    let mut journal_handle = match OpenOptions::new().write(true).create_new(true).open(&journal) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(Error::create(&journal, error));
+        }
+    };
+    match journal_handle.write_all(&journal_bytes).and_then(|_| journal_handle.sync_all()).and_then(|_| sync_parent_directory(&journal)) {
+        Ok(_resp) => {},
+        Err(error) => {
+            let _ = std::fs::remove_file(&journal);
+            return Err(Error::io(format!("Failed to write the journal '{}'", journal.display()), error));
+        }
+    };
+
+    // Write the new header over the old one
+    // If this fails, the journal is left behind so the next change to the key slots (or finish_interrupted_rewrite) can finish it
+    match write_header(file_handle, header_bytes) {
+        Ok(_resp) => {},
+        Err(error) => {
+            return Err(Error::io(format!("Failed to write the new header to '{}' (it will be finished from '{}' by the next change to the key slots, or by recover)", file.display(), journal.display()), error));
+        }
+    };
+
+    // The new header is safely on disk, so the journal isn't needed anymore
+    match std::fs::remove_file(&journal).and_then(|_| sync_parent_directory(&journal)) {
+        Ok(_resp) => Ok(()),
+        Err(error) => Err(Error::io(format!("Failed to remove the journal '{}'", journal.display()), error))
+    }
+}
+
+/// Finishes a change to the key slots of an encrypted file that was interrupted (I.E. by a crash or a power cut)
+/// 
+/// # Returns
+/// Whether an interrupted change was found and finished
+/// 
+/// # Notes
+/// While a header is being replaced, the new header is kept in a journal next to the file (`<file>.header-journal`).
+/// If the journal is complete, it's written over the header of the file. If the journal itself was only partly written, the old header was never touched, so the journal is removed
+pub fn finish_interrupted_rewrite<T: AsRef<Path>>(file: T) -> Result<bool> {
+    let journal = journal_path(file.as_ref());
+
+    // Read the journal, if there is one
+    let mut journal_bytes = Vec::new();
+    match File::open(&journal).and_then(|mut journal_handle| journal_handle.read_to_end(&mut journal_bytes)) {
+        Ok(_resp) => {},
+        Err(error) if error.kind() == ErrorKind::NotFound => {
+            return Ok(false);
+        },
+        Err(error) => {
+            return Err(Error::io(format!("Failed to read the journal '{}'", journal.display()), error));
+        }
+    };
+
+    // If the journal is incomplete, we crashed before the header was touched, so the old header is still intact
+    let is_complete = journal_bytes.len() > JOURNAL_HASH_SIZE && {
+        let (header_bytes, hash) = journal_bytes.split_at(journal_bytes.len() - JOURNAL_HASH_SIZE);
+        Blake2b::<U32>::digest(header_bytes).as_slice() == hash && Header::from_bytes(header_bytes).is_ok()
+    };
+    if !is_complete {
+        info!("Removing the incomplete journal '{}', the header of '{}' wasn't changed", journal.display(), file.as_ref().display());
+        return match std::fs::remove_file(&journal).and_then(|_| sync_parent_directory(&journal)) {
+            Ok(_resp) => Ok(false),
+            Err(error) => Err(Error::io(format!("Failed to remove the journal '{}'", journal.display()), error))
+        };
+    }
+    let header_bytes = &journal_bytes[..journal_bytes.len() - JOURNAL_HASH_SIZE];
+
+    // Make sure that the journal belongs to this file. The core of the header never changes, so it survives an interrupted write
+    let mut file_handle = match OpenOptions::new().read(true).write(true).open(&file) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(Error::io(format!("Couldn't open the encrypted file '{}'", file.as_ref().display()), error));
+        }
+    };
+    let mut core = [0u8; HEADER_CORE_SIZE];
+    match file_handle.read_exact(&mut core) {
+        Ok(_resp) => {},
+        Err(error) => {
+            return Err(Error::io(format!("Failed to read the header of '{}'", file.as_ref().display()), error));
+        }
+    };
+    if core[..] != header_bytes[..HEADER_CORE_SIZE] {
+        return Err(Error::Corrupted(format!("The journal '{}' doesn't belong to '{}'. Remove it if you're sure that it isn't needed", journal.display(), file.as_ref().display())));
+    }
+
+    // Finish writing the new header, then remove the journal
+    info!("Finishing an interrupted change to the key slots of '{}'", file.as_ref().display());
+    match write_header(&mut file_handle, header_bytes) {
+        Ok(_resp) => {},
+        Err(error) => {
+            return Err(Error::io(format!("Failed to write the new header to '{}'", file.as_ref().display()), error));
+        }
+    };
+    match std::fs::remove_file(&journal).and_then(|_| sync_parent_directory(&journal)) {
+        Ok(_resp) => Ok(true),
+        Err(error) => Err(Error::io(format!("Failed to remove the journal '{}'", journal.display()), error))
+    }
+}
+
+/// Returns true if a change to the key slots of an encrypted file was interrupted, and its journal is waiting to be finished (see `finish_interrupted_rewrite`)
+pub fn has_interrupted_rewrite<T: AsRef<Path>>(file: T) -> bool {
+    journal_path(file.as_ref()).symlink_metadata().is_ok()
+}
+
+/// Points out an interrupted change to the key slots, when the header of an encrypted file was found to be modified
+/// 
+/// # Notes
+/// A header that was only partly overwritten looks exactly like a modified header, so the journal is the only way to tell them apart.
+/// Every other error is returned as it is
+pub fn explain_modified_header<T: AsRef<Path>>(file: T, error: Error) -> Error {
+    match error {
+        Error::Corrupted(message) if has_interrupted_rewrite(&file) => Error::Corrupted(format!("{message}, or a change to its key slots was interrupted. The new header is in '{}', run recover on the file to finish the change", journal_path(file.as_ref()).display())),
+        other => other
+    }
+}
+
+// Returns the location of the journal of an encrypted file
+fn journal_path(file: &Path) -> PathBuf {
+    let mut journal = PathBuf::from(file);
+    journal.as_mut_os_string().push(JOURNAL_EXTENSION);
+    journal
+}
+
+// Writes the header over the start of the file, and makes sure that it reached the disk
+fn write_header(file: &mut File, header_bytes: &[u8]) -> std::io::Result<()> {
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(header_bytes)?;
+    file.sync_all()
+}
+
+// Makes sure that a file being created or removed in a directory reached the disk
+// Note: Directories can't be opened (or synced) like this on Windows, so this only does anything on Unix
+fn sync_parent_directory(path: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    if let Some(parent) = path.parent() {
+        let parent = if parent.as_os_str().is_empty() { Path::new(".") } else { parent };
+        return File::open(parent)?.sync_all();
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
@@ -1,4 +1,12 @@
 pub mod encryption;
+pub mod error;
 pub mod archiver;
-pub mod cli_args;
+pub mod builder;
+pub mod compression;
 pub mod file_handler;
+pub mod header;
+pub mod key_file;
+pub mod key_slots;
+pub mod recovery_key;
+pub mod shamir;
+pub mod x25519;
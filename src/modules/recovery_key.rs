@@ -0,0 +1,366 @@
+/*
+Copyright (C) 2023 Elijah Fry
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see https://www.gnu.org/licenses/gpl-3.0.html.
+*/
+
+use chacha20poly1305::aead::{OsRng, rand_core::RngCore};
+use zeroize::Zeroize;
+use crate::modules::{encryption::{keyed_hash, unwrap_file_key, wrap_file_key}, error::{Error, Result}, header::{Recipient, WRAPPED_KEY_SIZE}};
+
+
+/// The number of random bytes in a recovery key (160 bits, so it doesn't need to be hashed with Argon2id like a password)
+pub const RECOVERY_KEY_SIZE: usize = 20;
+
+// Recovery keys are written in Crockford's base32, which leaves out the letters that are easily confused with digits (I, L, O and U)
+const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+// The number of base32 characters in the key itself, and in the checksum at the end
+const KEY_CHARACTERS: usize = RECOVERY_KEY_SIZE * 8 / 5;
+const CHECKSUM_CHARACTERS: usize = 4;
+
+// The number of characters in every group, when the recovery key is written down
+const GROUP_SIZE: usize = 4;
+
+// The checksum is a linear code over GF(32) (the field of the base32 characters): every checksum character is the sum of the characters of the key, each multiplied by its own number from this table.
+// The rows of the table, along with one unit row for every checksum character, are points on an elliptic quadric, so no three of them are linearly dependent.
+// That means that every typo in up to three characters (I.E. two swapped characters) changes the checksum, and is caught
+const CHECKSUM_TABLE: [[u8; CHECKSUM_CHARACTERS]; KEY_CHARACTERS] = [
+    [4, 24, 4, 29], [8, 24, 11, 18], [13, 3, 17, 19], [8, 13, 17, 29],
+    [10, 27, 22, 12], [8, 17, 29, 13], [19, 8, 4, 13], [15, 24, 30, 7],
+    [27, 5, 8, 12], [25, 13, 30, 18], [31, 14, 20, 27], [22, 5, 21, 17],
+    [7, 30, 19, 12], [2, 19, 14, 28], [7, 7, 26, 28], [17, 15, 19, 29],
+    [13, 17, 19, 3], [7, 7, 28, 26], [25, 29, 25, 5], [17, 14, 26, 21],
+    [6, 23, 26, 12], [23, 23, 17, 7], [14, 2, 25, 26], [2, 10, 26, 17],
+    [20, 25, 20, 12], [30, 28, 15, 18], [26, 20, 8, 29], [5, 3, 1, 3],
+    [7, 29, 21, 9], [21, 23, 3, 21], [3, 16, 4, 21], [8, 27, 10, 16]
+];
+
+// The irreducible polynomial that defines GF(32), x^5 + x^2 + 1
+const GF32_POLYNOMIAL: u8 = 0x25;
+
+// The message hashed with the recovery key (and the salt) to derive the key that encrypts the file key
+const RECOVERY_KEY_MESSAGE: &[u8] = b"arch-crypt recovery key";
+
+
+/// Generates a new random recovery key
+/// 
+/// # Notes
+/// Show it to the user with `format_recovery_key`. It's only stored in the encrypted file in encrypted form, so it can't be shown again later
+pub fn generate_recovery_key() -> [u8; RECOVERY_KEY_SIZE] {
+    let mut recovery_key = [0u8; RECOVERY_KEY_SIZE];
+    OsRng.fill_bytes(&mut recovery_key);
+    recovery_key
+}
+
+/// Formats a recovery key so it can be written down, I.E. `7KQ2-...-X4M9`
+/// 
+/// # Notes
+/// The key is written in groups of 4 base32 characters, and the last group is a checksum that catches every typo in up to 3 characters
+pub fn format_recovery_key(recovery_key: &[u8; RECOVERY_KEY_SIZE]) -> String {
+    let mut values = encode_base32(recovery_key);
+    let checksum = checksum(&values);
+    values.extend_from_slice(&checksum);
+    let mut characters: Vec<u8> = values.iter().map(|value| ALPHABET[*value as usize]).collect();
+    values.zeroize();
+
+    let mut formatted = String::with_capacity(characters.len() + characters.len() / GROUP_SIZE);
+    for (index, group) in characters.chunks(GROUP_SIZE).enumerate() {
+        if index > 0 {
+            formatted.push('-');
+        }
+        formatted.extend(group.iter().map(|character| *character as char));
+    }
+    characters.zeroize();
+
+    formatted
+}
+
+/// Parses a recovery key that was written down from `format_recovery_key`
+/// 
+/// # Notes
+/// Dashes, spaces and case are ignored, and the letters I, L and O are read as the digits they look like
+pub fn parse_recovery_key(recovery_key: &str) -> Result<[u8; RECOVERY_KEY_SIZE]> {
+    // Turn every character back into its 5-bit value
+    let mut values = Vec::with_capacity(KEY_CHARACTERS + CHECKSUM_CHARACTERS);
+    for character in recovery_key.chars().filter(|character| *character != '-' && !character.is_whitespace()) {
+        let character = match character.to_ascii_uppercase() {
+            'I' | 'L' => '1',
+            'O' => '0',
+            other => other
+        };
+        match ALPHABET.iter().position(|letter| *letter as char == character) {
+            Some(value) => values.push(value as u8),
+            None => {
+                values.zeroize();
+                return Err(Error::Crypto(format!("The recovery key can't contain '{character}'")));
+            }
+        };
+    }
+    if values.len() != KEY_CHARACTERS + CHECKSUM_CHARACTERS {
+        let num_characters = values.len();
+        values.zeroize();
+        return Err(Error::Crypto(format!("The recovery key should have {} characters (not counting dashes), but it has {num_characters}", KEY_CHARACTERS + CHECKSUM_CHARACTERS)));
+    }
+
+    // Make sure that the checksum matches, then decode the key
+    let checksum_matches = checksum(&values[..KEY_CHARACTERS]) == values[KEY_CHARACTERS..];
+    if !checksum_matches {
+        values.zeroize();
+        return Err(Error::Crypto("The recovery key has a typo in it (its checksum doesn't match)".to_string()));
+    }
+    let recovery_key = decode_base32(&values[..KEY_CHARACTERS]);
+    values.zeroize();
+
+    Ok(recovery_key)
+}
+
+// Encodes bytes in base32, as the 5-bit values of the characters. The number of bits must be a multiple of 5
+fn encode_base32(bytes: &[u8]) -> Vec<u8> {
+    let mut values = Vec::with_capacity(bytes.len() * 8 / 5 + CHECKSUM_CHARACTERS);
+    let mut buffer: u32 = 0;
+    let mut num_bits = 0;
+    for byte in bytes {
+        buffer = (buffer << 8) | *byte as u32;
+        num_bits += 8;
+        while num_bits >= 5 {
+            num_bits -= 5;
+            values.push(((buffer >> num_bits) & 0x1f) as u8);
+        }
+    }
+    buffer.zeroize();
+    values
+}
+
+// Decodes KEY_CHARACTERS 5-bit values into the bytes of a recovery key
+fn decode_base32(values: &[u8]) -> [u8; RECOVERY_KEY_SIZE] {
+    let mut bytes = [0u8; RECOVERY_KEY_SIZE];
+    let mut buffer: u32 = 0;
+    let mut num_bits = 0;
+    let mut index = 0;
+    for value in values {
+        buffer = (buffer << 5) | *value as u32;
+        num_bits += 5;
+        if num_bits >= 8 {
+            num_bits -= 8;
+            bytes[index] = (buffer >> num_bits) as u8;
+            index += 1;
+        }
+    }
+    buffer.zeroize();
+    bytes
+}
+
+// Computes the 5-bit values of the checksum characters, from the KEY_CHARACTERS 5-bit values of a recovery key (see CHECKSUM_TABLE)
+fn checksum(values: &[u8]) -> [u8; CHECKSUM_CHARACTERS] {
+    let mut checksum = [0u8; CHECKSUM_CHARACTERS];
+    for (value, row) in values.iter().zip(CHECKSUM_TABLE.iter()) {
+        for (checksum_value, multiplier) in checksum.iter_mut().zip(row) {
+            // Addition in GF(32) is XOR
+            *checksum_value ^= gf32_multiply(*value, *multiplier);
+        }
+    }
+    checksum
+}
+
+// Multiplies two numbers in GF(32)
+fn gf32_multiply(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        b >>= 1;
+        a <<= 1;
+        if a & 0x20 != 0 {
+            a ^= GF32_POLYNOMIAL;
+        }
+    }
+    product
+}
+
+/// Encrypts the file key with a key derived from a recovery key
+pub(crate) fn wrap_for_recovery_key(file_key: &[u8; 32], recovery_key: &[u8; RECOVERY_KEY_SIZE]) -> Result<Recipient> {
+    // Derive the key that encrypts the file key, using a random salt
+    let mut salt = [0u8; 32];
+    OsRng.fill_bytes(&mut salt);
+    let mut wrap_key = match keyed_hash(recovery_key, RECOVERY_KEY_MESSAGE, &salt) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(error);
+        }
+    };
+    let wrapped_key = wrap_file_key(&wrap_key, file_key);
+    wrap_key.zeroize();
+
+    match wrapped_key {
+        Ok(resp) => Ok(Recipient::RecoveryKey {
+            salt,
+            wrapped_key: resp
+        }),
+        Err(error) => Err(error)
+    }
+}
+
+/// Decrypts the file key with a recovery key
+/// 
+/// # Returns
+/// The file key, or `None` if the recovery key is wrong
+pub(crate) fn unwrap_with_recovery_key(recovery_key: &[u8; RECOVERY_KEY_SIZE], salt: &[u8; 32], wrapped_key: &[u8; WRAPPED_KEY_SIZE]) -> Result<Option<[u8; 32]>> {
+    let mut wrap_key = match keyed_hash(recovery_key, RECOVERY_KEY_MESSAGE, salt) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(error);
+        }
+    };
+    let file_key = unwrap_file_key(&wrap_key, wrapped_key);
+    wrap_key.zeroize();
+
+    file_key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A fixed recovery key, so that every test is repeatable
+    const RECOVERY_KEY: [u8; RECOVERY_KEY_SIZE] = [
+        0x3a, 0x91, 0x07, 0xfe, 0x5c, 0x22, 0xb8, 0x4d, 0x10, 0xe3,
+        0x6f, 0x00, 0x9d, 0x71, 0xc4, 0x2b, 0x88, 0x15, 0xaa, 0x5e
+    ];
+
+    #[test]
+    fn formatted_recovery_keys_parse_back() {
+        for recovery_key in [RECOVERY_KEY, [0u8; RECOVERY_KEY_SIZE], [0xffu8; RECOVERY_KEY_SIZE], generate_recovery_key()] {
+            let formatted = format_recovery_key(&recovery_key);
+            assert_eq!(formatted.len(), 9 * GROUP_SIZE + 8);
+            assert_eq!(parse_recovery_key(&formatted).unwrap(), recovery_key);
+        }
+    }
+
+    // Replaces the characters at the given positions of a recovery key (written without dashes) with other characters
+    fn with_typos(compact: &str, typos: &[(usize, u8)]) -> String {
+        let mut characters = compact.as_bytes().to_vec();
+        for (position, typo) in typos {
+            characters[*position] = *typo;
+        }
+        String::from_utf8(characters).unwrap()
+    }
+
+    // Picks a random character of the alphabet that isn't `original`
+    fn random_typo(original: u8) -> u8 {
+        loop {
+            let typo = ALPHABET[(OsRng.next_u32() % 32) as usize];
+            if typo != original {
+                return typo;
+            }
+        }
+    }
+
+    #[test]
+    fn no_three_rows_of_the_checksum_table_are_dependent() {
+        // Every row of the table, and a unit row for every checksum character (a typo in the checksum itself)
+        let mut rows: Vec<[u8; CHECKSUM_CHARACTERS]> = CHECKSUM_TABLE.to_vec();
+        for index in 0..CHECKSUM_CHARACTERS {
+            let mut unit_row = [0u8; CHECKSUM_CHARACTERS];
+            unit_row[index] = 1;
+            rows.push(unit_row);
+        }
+        let multiply = |scalar: u8, row: &[u8; CHECKSUM_CHARACTERS]| row.map(|value| gf32_multiply(scalar, value));
+        let add = |a: [u8; CHECKSUM_CHARACTERS], b: [u8; CHECKSUM_CHARACTERS]| [a[0] ^ b[0], a[1] ^ b[1], a[2] ^ b[2], a[3] ^ b[3]];
+
+        // A typo in up to three characters goes unnoticed only if some multiples of their rows add up to zero, I.E. if one of the rows is in the span of the other two
+        for first in 0..rows.len() {
+            for second in first + 1..rows.len() {
+                let mut span = std::collections::HashSet::new();
+                for a in 0..32 {
+                    for b in 0..32 {
+                        span.insert(add(multiply(a, &rows[first]), multiply(b, &rows[second])));
+                    }
+                }
+                assert_eq!(span.len(), 32 * 32, "rows {first} and {second} are dependent");
+                for (third, row) in rows.iter().enumerate().skip(second + 1) {
+                    assert!(!span.contains(row), "rows {first}, {second} and {third} are dependent");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn typos_in_random_recovery_keys_are_caught() {
+        let num_characters = KEY_CHARACTERS + CHECKSUM_CHARACTERS;
+        for _ in 0..100 {
+            let compact = format_recovery_key(&generate_recovery_key()).replace('-', "");
+            let original = compact.as_bytes();
+
+            // Every single character typo
+            for (position, original_character) in original.iter().enumerate() {
+                for typo in ALPHABET.iter().filter(|letter| *letter != original_character) {
+                    let mistyped = with_typos(&compact, &[(position, *typo)]);
+                    assert!(parse_recovery_key(&mistyped).is_err(), "{mistyped} (a typo of {compact}) wasn't caught");
+                }
+            }
+
+            // Every pair of swapped neighbours
+            for position in 0..num_characters - 1 {
+                if original[position] != original[position + 1] {
+                    let swapped = with_typos(&compact, &[(position, original[position + 1]), (position + 1, original[position])]);
+                    assert!(parse_recovery_key(&swapped).is_err(), "{swapped} (a typo of {compact}) wasn't caught");
+                }
+            }
+
+            // Random typos in two and three characters
+            for num_typos in [2, 3] {
+                for _ in 0..100 {
+                    let mut positions: Vec<usize> = Vec::new();
+                    while positions.len() < num_typos {
+                        let position = OsRng.next_u32() as usize % num_characters;
+                        if !positions.contains(&position) {
+                            positions.push(position);
+                        }
+                    }
+                    let typos: Vec<(usize, u8)> = positions.iter().map(|position| (*position, random_typo(original[*position]))).collect();
+                    let mistyped = with_typos(&compact, &typos);
+                    assert!(parse_recovery_key(&mistyped).is_err(), "{mistyped} (a typo of {compact}) wasn't caught");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn separators_and_case_are_ignored() {
+        let formatted = format_recovery_key(&RECOVERY_KEY);
+        let variants = [
+            formatted.to_lowercase(),
+            formatted.replace('-', ""),
+            formatted.replace('-', " "),
+            format!("  {}\n", formatted.replace('-', " - ")),
+            // I and L look like 1, and O looks like 0
+            formatted.to_lowercase().replace('1', "l").replace('0', "O"),
+            formatted.replace('1', "I")
+        ];
+        for variant in variants {
+            assert_eq!(parse_recovery_key(&variant).unwrap(), RECOVERY_KEY, "'{variant}' wasn't parsed");
+        }
+    }
+
+    #[test]
+    fn malformed_recovery_keys_are_rejected() {
+        let formatted = format_recovery_key(&RECOVERY_KEY);
+        assert!(parse_recovery_key(&formatted[..formatted.len() - 1]).is_err());
+        assert!(parse_recovery_key(&format!("{formatted}0")).is_err());
+        assert!(parse_recovery_key(&formatted.replacen(|character: char| character.is_ascii_alphanumeric(), "U", 1)).is_err());
+        assert!(parse_recovery_key("").is_err());
+    }
+}
@@ -0,0 +1,417 @@
+/*
+Copyright (C) 2023 Elijah Fry
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see https://www.gnu.org/licenses/gpl-3.0.html.
+*/
+
+use std::path::{Path, PathBuf};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use chacha20poly1305::aead::{OsRng, rand_core::RngCore};
+use zeroize::Zeroize;
+use crate::modules::{encryption::{keyed_hash, unwrap_file_key, wrap_file_key}, error::{Error, Result}, header::{Recipient, WRAPPED_KEY_SIZE}, x25519::write_new_file};
+
+
+/// The number of random bytes that identify the shares split from the same key
+pub(crate) const SPLIT_ID_SIZE: usize = 16;
+
+// The prefix of the line that holds the share, in a share file
+const SHARE_PREFIX: &str = "arch-crypt-share:";
+
+// The number of bytes in an encoded share: the split ID, the threshold, the number of shares, the index and the value
+const ENCODED_SHARE_SIZE: usize = SPLIT_ID_SIZE + 3 + 32;
+
+// The message hashed with the split key (and the split ID) to derive the key that encrypts the file key
+const SHARES_KEY_MESSAGE: &[u8] = b"arch-crypt shamir shares key";
+
+/// The smallest number of shares that a key can be split for (with a threshold of 1, every share would just be a copy of the key)
+pub const MIN_THRESHOLD: u8 = 2;
+
+
+/// One share of a key that was split with Shamir's secret sharing. Any `threshold` shares of the same split can decrypt the file
+/// 
+/// # Notes
+/// The value of the share is overwritten with 0s when the `Share` is dropped
+pub struct Share {
+    split_id: [u8; SPLIT_ID_SIZE],
+    threshold: u8,
+    num_shares: u8,
+    index: u8,
+    value: [u8; 32]
+}
+
+impl Share {
+    /// Returns the number of the share, from 1 to the number of shares
+    pub fn index(&self) -> u8 {
+        self.index
+    }
+
+    /// Returns the number of shares that are needed to decrypt the file
+    pub fn threshold(&self) -> u8 {
+        self.threshold
+    }
+
+    /// Returns the number of shares that the key was split into
+    pub fn num_shares(&self) -> u8 {
+        self.num_shares
+    }
+
+    // Encodes the share the way it's written in a share file
+    fn to_bytes(&self) -> [u8; ENCODED_SHARE_SIZE] {
+        let mut bytes = [0u8; ENCODED_SHARE_SIZE];
+        bytes[..SPLIT_ID_SIZE].copy_from_slice(&self.split_id);
+        bytes[SPLIT_ID_SIZE] = self.threshold;
+        bytes[SPLIT_ID_SIZE + 1] = self.num_shares;
+        bytes[SPLIT_ID_SIZE + 2] = self.index;
+        bytes[SPLIT_ID_SIZE + 3..].copy_from_slice(&self.value);
+        bytes
+    }
+}
+
+impl Drop for Share {
+    fn drop(&mut self) {
+        self.value.zeroize();
+    }
+}
+
+/// Generates a random key and splits it into shares, so that any `threshold` of them can decrypt the file
+/// 
+/// # Arguments
+/// * `threshold` - The number of shares that are needed to decrypt the file
+/// * `num_shares` - The number of shares to create, I.E. one for every custodian
+/// 
+/// # Notes
+/// The key itself is never stored. The encrypted file only holds the file key, encrypted with it, so write every share out with `write_share_files`
+pub fn generate_shares(threshold: u8, num_shares: u8) -> Result<Vec<Share>> {
+    if threshold < MIN_THRESHOLD || threshold > num_shares {
+        return Err(Error::Crypto(format!("Can't split a key into {num_shares} shares with a threshold of {threshold} (the threshold must be between {MIN_THRESHOLD} and the number of shares)")));
+    }
+
+    // Generate the key, and the ID that ties its shares together
+    let mut split_key = [0u8; 32];
+    OsRng.fill_bytes(&mut split_key);
+    let mut split_id = [0u8; SPLIT_ID_SIZE];
+    OsRng.fill_bytes(&mut split_id);
+
+    // Every byte of the key is the constant term of its own random polynomial of degree threshold - 1, and share x is every polynomial evaluated at x
+    let mut shares: Vec<Share> = (1..=num_shares).map(|index| Share {
+        split_id,
+        threshold,
+        num_shares,
+        index,
+        value: [0u8; 32]
+    }).collect();
+    let mut coefficients = vec![0u8; usize::from(threshold)];
+    for (byte_index, byte) in split_key.iter().enumerate() {
+        coefficients[0] = *byte;
+        OsRng.fill_bytes(&mut coefficients[1..]);
+        for share in shares.iter_mut() {
+            share.value[byte_index] = coefficients.iter().rev().fold(0, |value, coefficient| gf_multiply(value, share.index) ^ coefficient);
+        }
+    }
+    coefficients.zeroize();
+    split_key.zeroize();
+
+    Ok(shares)
+}
+
+/// Writes every share to its own file, named after the encrypted file with `.share-<index>` appended
+/// 
+/// # Returns
+/// The paths of the share files, in the same order as the shares
+/// 
+/// # Notes
+/// Share files are never overwritten, and (on Unix) only the owner can read them. If one of them can't be written, the ones that were already written are removed
+pub fn write_share_files<T: AsRef<Path>>(encrypted_file: T, shares: &[Share]) -> Result<Vec<PathBuf>> {
+    let mut share_files = Vec::with_capacity(shares.len());
+    for share in shares {
+        let mut share_file = PathBuf::from(encrypted_file.as_ref());
+        share_file.as_mut_os_string().push(format!(".share-{}", share.index));
+
+        let mut bytes = share.to_bytes();
+        let mut contents = format!("# arch-crypt share {} of {}, any {} of them can decrypt the file\n{SHARE_PREFIX}{}\n", share.index, share.num_shares, share.threshold, STANDARD.encode(bytes));
+        bytes.zeroize();
+        let result = write_new_file(&share_file, contents.as_bytes(), true);
+        contents.zeroize();
+        match result {
+            Ok(_resp) => share_files.push(share_file),
+            Err(error) => {
+                for share_file in &share_files {
+                    let _ = std::fs::remove_file(share_file);
+                }
+                return Err(error);
+            }
+        };
+    }
+
+    Ok(share_files)
+}
+
+/// Reads a share file created by `write_share_files`, and returns the share in it
+pub fn read_share_file<T: AsRef<Path>>(share_file: T) -> Result<Share> {
+    let mut contents = match std::fs::read_to_string(&share_file) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(Error::io(format!("Couldn't read the share file '{}'", share_file.as_ref().display()), error));
+        }
+    };
+
+    // Find the line that holds the share (the others are comments)
+    let mut decoded = match contents.lines().find_map(|line| line.trim().strip_prefix(SHARE_PREFIX)) {
+        Some(encoded) => STANDARD.decode(encoded).unwrap_or_default(),
+        None => Vec::new()
+    };
+    contents.zeroize();
+    let is_valid = decoded.len() == ENCODED_SHARE_SIZE && {
+        let (threshold, num_shares, index) = (decoded[SPLIT_ID_SIZE], decoded[SPLIT_ID_SIZE + 1], decoded[SPLIT_ID_SIZE + 2]);
+        threshold >= MIN_THRESHOLD && threshold <= num_shares && index > 0 && index <= num_shares
+    };
+    if !is_valid {
+        decoded.zeroize();
+        return Err(Error::Crypto(format!("'{}' isn't a share file", share_file.as_ref().display())));
+    }
+
+    let mut share = Share {
+        split_id: [0u8; SPLIT_ID_SIZE],
+        threshold: decoded[SPLIT_ID_SIZE],
+        num_shares: decoded[SPLIT_ID_SIZE + 1],
+        index: decoded[SPLIT_ID_SIZE + 2],
+        value: [0u8; 32]
+    };
+    share.split_id.copy_from_slice(&decoded[..SPLIT_ID_SIZE]);
+    share.value.copy_from_slice(&decoded[SPLIT_ID_SIZE + 3..]);
+    decoded.zeroize();
+
+    Ok(share)
+}
+
+/// Returns the shares that were split from the key with the given ID, leaving out duplicates
+pub(crate) fn matching_shares<'a>(shares: &'a [Share], split_id: &[u8; SPLIT_ID_SIZE]) -> Vec<&'a Share> {
+    let mut matching: Vec<&Share> = Vec::new();
+    for share in shares.iter().filter(|share| share.split_id == *split_id) {
+        if !matching.iter().any(|other| other.index == share.index) {
+            matching.push(share);
+        }
+    }
+    matching
+}
+
+/// Encrypts the file key with the key that the shares were split from
+/// 
+/// # Notes
+/// Every share must come from the same split, and at least `threshold` of them are needed to recover the key
+pub(crate) fn wrap_for_shares(file_key: &[u8; 32], shares: &[Share]) -> Result<Recipient> {
+    let (split_id, threshold, num_shares) = match shares.first() {
+        Some(share) => (share.split_id, share.threshold, share.num_shares),
+        None => {
+            return Err(Error::Crypto("No shares were given".to_string()));
+        }
+    };
+    if shares.iter().any(|share| share.split_id != split_id) {
+        return Err(Error::Crypto("The shares weren't all split from the same key".to_string()));
+    }
+    let matching = matching_shares(shares, &split_id);
+    if matching.len() < usize::from(threshold) {
+        return Err(Error::Crypto(format!("Only {} of the {threshold} shares needed to recover the key were given", matching.len())));
+    }
+
+    let mut wrap_key = match derive_wrap_key(&matching[..usize::from(threshold)], &split_id) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(error);
+        }
+    };
+    let wrapped_key = wrap_file_key(&wrap_key, file_key);
+    wrap_key.zeroize();
+
+    match wrapped_key {
+        Ok(resp) => Ok(Recipient::Shares {
+            split_id,
+            threshold,
+            num_shares,
+            wrapped_key: resp
+        }),
+        Err(error) => Err(error)
+    }
+}
+
+/// Decrypts the file key with shares of the key that it was encrypted with
+/// 
+/// # Returns
+/// The file key, or `None` if fewer than `threshold` shares of the split were given, or they don't recover the right key
+pub(crate) fn unwrap_with_shares(shares: &[Share], split_id: &[u8; SPLIT_ID_SIZE], threshold: u8, wrapped_key: &[u8; WRAPPED_KEY_SIZE]) -> Result<Option<[u8; 32]>> {
+    let matching = matching_shares(shares, split_id);
+    if threshold < MIN_THRESHOLD || matching.len() < usize::from(threshold) {
+        return Ok(None);
+    }
+
+    let mut wrap_key = match derive_wrap_key(&matching[..usize::from(threshold)], split_id) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(error);
+        }
+    };
+    let file_key = unwrap_file_key(&wrap_key, wrapped_key);
+    wrap_key.zeroize();
+
+    file_key
+}
+
+// Recovers the split key from the shares with Lagrange interpolation at x = 0, and derives the key that encrypts the file key from it
+fn derive_wrap_key(shares: &[&Share], split_id: &[u8; SPLIT_ID_SIZE]) -> Result<[u8; 32]> {
+    let mut split_key = [0u8; 32];
+    for share in shares {
+        // The Lagrange basis polynomial of this share, evaluated at 0 (subtraction is XOR in GF(256))
+        let basis = shares.iter().filter(|other| other.index != share.index).fold(1, |basis, other| {
+            gf_multiply(basis, gf_multiply(other.index, gf_inverse(other.index ^ share.index)))
+        });
+        for (byte, value) in split_key.iter_mut().zip(share.value.iter()) {
+            *byte ^= gf_multiply(*value, basis);
+        }
+    }
+
+    let wrap_key = keyed_hash(&split_key, SHARES_KEY_MESSAGE, split_id);
+    split_key.zeroize();
+    wrap_key
+}
+
+// Multiplies two elements of GF(256) (with the AES polynomial), without branching on their values
+fn gf_multiply(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    for _ in 0..8 {
+        product ^= a & (b & 1).wrapping_neg();
+        a = (a << 1) ^ ((a >> 7).wrapping_neg() & 0x1b);
+        b >>= 1;
+    }
+    product
+}
+
+// Inverts an element of GF(256), as a^254
+fn gf_inverse(a: u8) -> u8 {
+    let mut result = 1;
+    let mut power = a;
+    let mut exponent: u8 = 254;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = gf_multiply(result, power);
+        }
+        power = gf_multiply(power, power);
+        exponent >>= 1;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE_KEY: [u8; 32] = [0x42; 32];
+
+    // Shares can't be cloned (so their values aren't copied around), but the tests need the same share in more than one subset
+    fn copy_share(share: &Share) -> Share {
+        Share {
+            split_id: share.split_id,
+            threshold: share.threshold,
+            num_shares: share.num_shares,
+            index: share.index,
+            value: share.value
+        }
+    }
+
+    // Wraps FILE_KEY for the shares, and unwraps it again with the shares at the given positions
+    fn unwrap_with_subset(shares: &[Share], subset: &[usize]) -> Option<[u8; 32]> {
+        let (split_id, threshold, wrapped_key) = match wrap_for_shares(&FILE_KEY, shares).unwrap() {
+            Recipient::Shares { split_id, threshold, wrapped_key, .. } => (split_id, threshold, wrapped_key),
+            _ => unreachable!()
+        };
+        let subset: Vec<Share> = subset.iter().map(|position| copy_share(&shares[*position])).collect();
+        unwrap_with_shares(&subset, &split_id, threshold, &wrapped_key).unwrap()
+    }
+
+    #[test]
+    fn every_inverse_multiplies_to_one() {
+        for a in 1..=255u8 {
+            assert_eq!(gf_multiply(a, gf_inverse(a)), 1, "{a} * {a}^-1 isn't 1");
+        }
+    }
+
+    #[test]
+    fn multiplication_matches_the_aes_field() {
+        // The example from FIPS-197, section 4.2
+        assert_eq!(gf_multiply(0x57, 0x83), 0xc1);
+        assert_eq!(gf_multiply(0x57, 0x13), 0xfe);
+        for a in 0..=255u8 {
+            assert_eq!(gf_multiply(a, 1), a);
+            assert_eq!(gf_multiply(a, 0), 0);
+            assert_eq!(gf_multiply(a, 0x02), gf_multiply(0x02, a));
+        }
+    }
+
+    #[test]
+    fn every_two_of_three_subset_recovers_the_key() {
+        let shares = generate_shares(2, 3).unwrap();
+        for subset in [[0, 1], [0, 2], [1, 2], [1, 0], [2, 0], [2, 1]] {
+            assert_eq!(unwrap_with_subset(&shares, &subset), Some(FILE_KEY), "shares {subset:?} didn't recover the key");
+        }
+        assert_eq!(unwrap_with_subset(&shares, &[0, 1, 2]), Some(FILE_KEY));
+    }
+
+    #[test]
+    fn every_three_of_five_subset_recovers_the_key() {
+        let shares = generate_shares(3, 5).unwrap();
+        for a in 0..5 {
+            for b in a + 1..5 {
+                assert_eq!(unwrap_with_subset(&shares, &[a, b]), None, "shares {a} and {b} recovered the key without a third");
+                for c in b + 1..5 {
+                    assert_eq!(unwrap_with_subset(&shares, &[a, b, c]), Some(FILE_KEY), "shares {a}, {b} and {c} didn't recover the key");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn a_single_share_does_not_recover_the_key() {
+        let shares = generate_shares(2, 3).unwrap();
+        for position in 0..3 {
+            assert_eq!(unwrap_with_subset(&shares, &[position]), None);
+        }
+    }
+
+    #[test]
+    fn duplicate_shares_only_count_once() {
+        let shares = generate_shares(2, 3).unwrap();
+        assert_eq!(unwrap_with_subset(&shares, &[0, 0]), None);
+        assert_eq!(unwrap_with_subset(&shares, &[2, 2, 2]), None);
+        assert_eq!(matching_shares(&[copy_share(&shares[1]), copy_share(&shares[1]), copy_share(&shares[0])], &shares[0].split_id).len(), 2);
+    }
+
+    #[test]
+    fn shares_of_another_split_are_ignored() {
+        let shares = generate_shares(2, 3).unwrap();
+        let other_shares = generate_shares(2, 3).unwrap();
+        let (split_id, threshold, wrapped_key) = match wrap_for_shares(&FILE_KEY, &shares).unwrap() {
+            Recipient::Shares { split_id, threshold, wrapped_key, .. } => (split_id, threshold, wrapped_key),
+            _ => unreachable!()
+        };
+        let mixed = [copy_share(&shares[0]), copy_share(&other_shares[1])];
+        assert_eq!(unwrap_with_shares(&mixed, &split_id, threshold, &wrapped_key).unwrap(), None);
+    }
+
+    #[test]
+    fn thresholds_below_two_or_above_the_number_of_shares_are_rejected() {
+        assert!(generate_shares(0, 3).is_err());
+        assert!(generate_shares(1, 3).is_err());
+        assert!(generate_shares(4, 3).is_err());
+        assert!(generate_shares(2, 2).is_ok());
+    }
+}
@@ -0,0 +1,227 @@
+/*
+Copyright (C) 2023 Elijah Fry
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see https://www.gnu.org/licenses/gpl-3.0.html.
+*/
+
+use std::{fs::{File, OpenOptions}, io::{Read, Write}, path::{Path, PathBuf}};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use chacha20poly1305::aead::OsRng;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+use zeroize::Zeroize;
+use crate::modules::{encryption::{keyed_hash, unwrap_file_key, wrap_file_key}, error::{Error, Result}, header::{Recipient, WRAPPED_KEY_SIZE}};
+
+
+/// The prefix of an X25519 public key, as shown to the user (I.E. `x25519:BASE64`)
+pub const PUBLIC_KEY_PREFIX: &str = "x25519:";
+
+// The prefix of the line that holds the private key, in a private key file
+const PRIVATE_KEY_PREFIX: &str = "x25519-private:";
+
+// The message hashed with the shared secret (and both public keys) to derive the key that encrypts the file key
+const X25519_KEY_MESSAGE: &[u8] = b"arch-crypt x25519 key";
+
+
+/// Parses an X25519 public key, as printed by `generate_key_pair`
+/// 
+/// # Arguments
+/// * `public_key` - The public key, I.E. `x25519:BASE64`
+pub fn parse_public_key(public_key: &str) -> Result<[u8; 32]> {
+    let encoded = match public_key.trim().strip_prefix(PUBLIC_KEY_PREFIX) {
+        Some(resp) => resp,
+        None => {
+            return Err(Error::Crypto(format!("'{public_key}' isn't an X25519 public key (it should start with '{PUBLIC_KEY_PREFIX}')")));
+        }
+    };
+
+    match STANDARD.decode(encoded) {
+        Ok(resp) if resp.len() == 32 => {
+            let mut key = [0u8; 32];
+            key.copy_from_slice(&resp);
+            Ok(key)
+        },
+        _ => Err(Error::Crypto(format!("'{public_key}' isn't a valid X25519 public key")))
+    }
+}
+
+/// Formats an X25519 public key the way it's shown to the user (I.E. `x25519:BASE64`)
+pub fn format_public_key(public_key: &[u8; 32]) -> String {
+    format!("{PUBLIC_KEY_PREFIX}{}", STANDARD.encode(public_key))
+}
+
+/// Reads an X25519 private key file created by `generate_key_pair`, and returns the private key in it
+pub fn read_private_key<T: AsRef<Path>>(private_key_file: T) -> Result<[u8; 32]> {
+    let mut file = match File::open(&private_key_file) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(Error::io(format!("Couldn't open the private key file '{}'", private_key_file.as_ref().display()), error));
+        }
+    };
+
+    // Private key files are small, so read the whole thing
+    let mut contents = String::new();
+    match file.read_to_string(&mut contents) {
+        Ok(_resp) => {},
+        Err(error) => {
+            contents.zeroize();
+            return Err(Error::io(format!("Failed to read the private key file '{}'", private_key_file.as_ref().display()), error));
+        }
+    };
+
+    // Find the line that holds the private key (the others are comments)
+    let mut decoded = match contents.lines().find_map(|line| line.trim().strip_prefix(PRIVATE_KEY_PREFIX)) {
+        Some(encoded) => STANDARD.decode(encoded).unwrap_or_default(),
+        None => Vec::new()
+    };
+    contents.zeroize();
+    if decoded.len() != 32 {
+        decoded.zeroize();
+        return Err(Error::Crypto(format!("'{}' isn't an X25519 private key file", private_key_file.as_ref().display())));
+    }
+
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&decoded);
+    decoded.zeroize();
+
+    Ok(key)
+}
+
+/// Creates a new X25519 key pair, writing the private key to `private_key_file` and the public key to the same path with `.pub` appended
+/// 
+/// # Returns
+/// The public key, which is what other people need to encrypt files for you
+/// 
+/// # Notes
+/// Neither file is overwritten if it already exists, and (on Unix) only the owner can read the private key file
+pub fn generate_key_pair<T: AsRef<Path>>(private_key_file: T) -> Result<String> {
+    let private_key_file = private_key_file.as_ref();
+    let mut public_key_file = PathBuf::from(private_key_file);
+    public_key_file.as_mut_os_string().push(".pub");
+
+    // Generate the key pair
+    let secret = StaticSecret::random_from_rng(OsRng);
+    let public_key = format_public_key(PublicKey::from(&secret).as_bytes());
+    let mut contents = format!("# public key: {public_key}\n{PRIVATE_KEY_PREFIX}{}\n", STANDARD.encode(secret.as_bytes()));
+
+    // Write the private key first, so we never leave a public key behind without its private key
+    let result = write_new_file(private_key_file, contents.as_bytes(), true);
+    contents.zeroize();
+    match result {
+        Ok(_resp) => {},
+        Err(error) => {
+            return Err(error);
+        }
+    };
+    match write_new_file(&public_key_file, format!("{public_key}\n").as_bytes(), false) {
+        Ok(_resp) => {},
+        Err(error) => {
+            let _ = std::fs::remove_file(private_key_file);
+            return Err(error);
+        }
+    };
+
+    Ok(public_key)
+}
+
+/// Creates a new file with the given contents, and makes sure that it reached the disk
+/// 
+/// # Notes
+/// The file is never overwritten if it already exists. If `private` is true, (on Unix) only the owner can read it
+pub(crate) fn write_new_file(path: &Path, contents: &[u8], private: bool) -> Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+    let mut file = match options.open(path) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(Error::create(path, error));
+        }
+    };
+
+    match file.write_all(contents).and_then(|_| file.sync_all()) {
+        Ok(_resp) => Ok(()),
+        Err(error) => {
+            let _ = std::fs::remove_file(path);
+            Err(Error::io(format!("Failed to write '{}'", path.display()), error))
+        }
+    }
+}
+
+/// Encrypts the file key for the owner of an X25519 public key
+/// 
+/// # Notes
+/// A new ephemeral key is used every time, and the key that encrypts the file key is derived from the shared secret and both public keys
+pub(crate) fn wrap_for_public_key(file_key: &[u8; 32], public_key: &[u8; 32]) -> Result<Recipient> {
+    let ephemeral_secret = EphemeralSecret::random_from_rng(OsRng);
+    let ephemeral_public_key = PublicKey::from(&ephemeral_secret).to_bytes();
+    let shared_secret = ephemeral_secret.diffie_hellman(&PublicKey::from(*public_key));
+    if !shared_secret.was_contributory() {
+        return Err(Error::Crypto(format!("'{}' isn't a valid X25519 public key", format_public_key(public_key))));
+    }
+
+    let mut wrap_key = match derive_wrap_key(shared_secret.as_bytes(), &ephemeral_public_key, public_key) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(error);
+        }
+    };
+    let wrapped_key = wrap_file_key(&wrap_key, file_key);
+    wrap_key.zeroize();
+
+    match wrapped_key {
+        Ok(resp) => Ok(Recipient::X25519 {
+            ephemeral_public_key,
+            wrapped_key: resp
+        }),
+        Err(error) => Err(error)
+    }
+}
+
+/// Decrypts the file key with an X25519 private key
+/// 
+/// # Returns
+/// The file key, or `None` if the file key wasn't encrypted for this private key
+pub(crate) fn unwrap_with_private_key(private_key: &[u8; 32], ephemeral_public_key: &[u8; 32], wrapped_key: &[u8; WRAPPED_KEY_SIZE]) -> Result<Option<[u8; 32]>> {
+    let secret = StaticSecret::from(*private_key);
+    let public_key = PublicKey::from(&secret).to_bytes();
+    let shared_secret = secret.diffie_hellman(&PublicKey::from(*ephemeral_public_key));
+    if !shared_secret.was_contributory() {
+        return Ok(None);
+    }
+
+    let mut wrap_key = match derive_wrap_key(shared_secret.as_bytes(), ephemeral_public_key, &public_key) {
+        Ok(resp) => resp,
+        Err(error) => {
+            return Err(error);
+        }
+    };
+    let file_key = unwrap_file_key(&wrap_key, wrapped_key);
+    wrap_key.zeroize();
+
+    file_key
+}
+
+// Derives the key that encrypts the file key from the shared secret, binding it to both public keys
+fn derive_wrap_key(shared_secret: &[u8; 32], ephemeral_public_key: &[u8; 32], public_key: &[u8; 32]) -> Result<[u8; 32]> {
+    let mut public_keys = [0u8; 64];
+    public_keys[..32].copy_from_slice(ephemeral_public_key);
+    public_keys[32..].copy_from_slice(public_key);
+    keyed_hash(shared_secret, X25519_KEY_MESSAGE, &public_keys)
+}